@@ -10,6 +10,20 @@ impl ArithmeticRenderer {
             .into()
     }
 
+    /// Abbreviates large numbers, e.g. `2710000000000` as `2.71T`.
+    pub fn render_suffixed(&self, value: f64) -> String {
+        let suffixes = [(1e12, "T"), (1e9, "B"), (1e6, "M"), (1e3, "K")];
+        match suffixes
+            .into_iter()
+            .find(|(threshold, _)| value.abs() >= *threshold)
+        {
+            Some((threshold, suffix)) => {
+                format!("{}{}", self.render_float(value / threshold), suffix)
+            }
+            None => self.render_float(value),
+        }
+    }
+
     pub fn render_percentage(&self, percentage: &Notional) -> String {
         format!("{}%", self.render_float(percentage.value * 100.0))
     }
@@ -27,6 +41,13 @@ mod test {
         ArithmeticRenderer.render_float(value)
     }
 
+    #[case(2.71e12 => "2.71T"  ; "Trillions")]
+    #[case(-4.5e6 => "-4.5M"   ; "Negative millions")]
+    #[case(999.0 => "999"      ; "No suffix")]
+    fn render_suffixed(value: f64) -> String {
+        ArithmeticRenderer.render_suffixed(value)
+    }
+
     #[test]
     fn render_change() {
         assert_eq!(
@@ -0,0 +1,88 @@
+use crate::stock_data_cacher::write_private;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Price and volume of a contract over one bar of time.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct Bar {
+    pub time: DateTime<Utc>,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+}
+
+/// Keeps the bars of each contract in a JSON file on disk.
+#[derive(Default)]
+pub struct BarStore {
+    dir: PathBuf,
+}
+
+#[mockall::automock]
+impl BarStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Loads the stored bars sorted by time, or nothing if none is stored yet.
+    pub async fn load(&self, conid: i64, bar: &str) -> anyhow::Result<Vec<Bar>> {
+        let path = self.path(conid, bar);
+        if !path.exists() {
+            return Ok(Vec::default());
+        }
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub async fn save(&self, conid: i64, bar: &str, bars: &[Bar]) -> anyhow::Result<()> {
+        let path = self.path(conid, bar);
+        let text = serde_json::to_string(bars)?;
+        write_private(&self.dir, &path, text.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+impl BarStore {
+    fn path(&self, conid: i64, bar: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}.json", conid, bar))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn save_and_load() {
+        // Given
+        let dir =
+            std::env::temp_dir().join(format!("ibkr-toy-test-bar-store-{}", std::process::id()));
+        let store = BarStore::new(dir.clone());
+        let bars = vec![Bar {
+            time: DateTime::from_timestamp(86400, 0).unwrap(),
+            open: 1.0,
+            high: 2.0,
+            low: 0.5,
+            close: 1.5,
+            volume: 100.0,
+        }];
+
+        // When
+        let missing_bars = store.load(1, "1d").await.unwrap();
+        store.save(1, "1d", &bars).await.unwrap();
+        let loaded_bars = store.load(1, "1d").await.unwrap();
+
+        // Then
+        assert!(missing_bars.is_empty());
+        assert_eq!(bars, loaded_bars);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,487 @@
+use crate::market_calendar::ExchangeCalendar;
+use crate::scoring_factor_extractor::ScoringFactor;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// User configuration loaded from a YAML file.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Config {
+    pub gateway: GatewayConfig,
+    pub snapshot: SnapshotConfig,
+    pub history: HistoryConfig,
+    pub ranking: RankingConfig,
+    pub portfolio: PortfolioConfig,
+    pub cache: CacheConfig,
+    pub calendar: CalendarConfig,
+    pub archive: ArchiveConfig,
+    pub universe: UniverseConfig,
+
+    /// Market scanners run by the `scan` command, by name.
+    pub scanners: HashMap<String, ScannerConfig>,
+}
+
+impl Config {
+    /// Loads the configuration at `path`, or the default one if the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        serde_yaml::from_str(&text)
+            .with_context(|| format!("Failed to parse config {}", path.display()))
+    }
+
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::config_dir().unwrap_or_else(std::env::temp_dir);
+        path.push("ibkr-toy");
+        path.push("config.yaml");
+        path
+    }
+}
+
+/// Where and how to reach the Client Portal Gateway.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// Base URL of the REST API, e.g. `https://127.0.0.1:5000/v1/api/`.
+    pub base_url: String,
+
+    /// Overrides the port in `base_url`.
+    pub port: Option<u16>,
+
+    /// PEM bundle of the CA that signed the gateway certificate.
+    pub ca_certificate: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the gateway certificate, in hex with or without colons.
+    ///
+    /// When set, the certificate is trusted if and only if its fingerprint matches,
+    /// checked during the TLS handshake. Cannot be combined with `insecure`.
+    pub certificate_fingerprint: Option<String>,
+
+    /// Accepts any certificate. Only meant for a gateway on the loopback interface.
+    pub insecure: bool,
+
+    pub retry: RetryConfig,
+    pub http: HttpConfig,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://127.0.0.1:5000/v1/api/".into(),
+            port: None,
+            ca_certificate: None,
+            certificate_fingerprint: None,
+            insecure: false,
+            retry: Default::default(),
+            http: Default::default(),
+        }
+    }
+}
+
+/// Timeouts and connection pooling of the HTTP client.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub connect_timeout_millis: u64,
+
+    /// Maximum time waiting for the response headers or the body.
+    pub read_timeout_millis: u64,
+
+    /// Maximum time of a whole request.
+    pub total_timeout_millis: u64,
+
+    pub pool_idle_timeout_millis: u64,
+    pub pool_max_idle: usize,
+    pub tcp_keepalive_millis: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_millis: 5_000,
+            read_timeout_millis: 30_000,
+            total_timeout_millis: 60_000,
+            pool_idle_timeout_millis: 90_000,
+            pool_max_idle: 8,
+            tcp_keepalive_millis: 60_000,
+        }
+    }
+}
+
+/// How failed requests are retried with exponential backoff.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub initial_backoff_millis: u64,
+    pub max_backoff_millis: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff_millis: 500,
+            max_backoff_millis: 10_000,
+        }
+    }
+}
+
+/// How long to wait for the gateway to fill in the market snapshot.
+///
+/// The first snapshot request of a contract only subscribes to its market data, so the response
+/// usually lacks most of the fields.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    pub warm_up_deadline_millis: u64,
+    pub poll_interval_millis: u64,
+
+    /// Maximum number of contracts in one request.
+    pub chunk_size: usize,
+
+    /// Maximum number of requests in flight.
+    pub concurrency: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            warm_up_deadline_millis: 10_000,
+            poll_interval_millis: 1_000,
+            chunk_size: 50,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Where and for how long downloaded stock data is kept.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Defaults to `ibkr-toy/stock-data` in the user cache directory.
+    pub dir: Option<PathBuf>,
+
+    /// How long cached data counts as fresh if none of its exchanges is in the calendar.
+    pub ttl_millis: u64,
+
+    /// Directory of the contract details. Defaults to `ibkr-toy/contracts` in the user cache directory.
+    pub contracts_dir: Option<PathBuf>,
+
+    /// How long contract details are kept, so that renamed or delisted contracts get refreshed.
+    pub contracts_ttl_millis: u64,
+}
+
+impl CacheConfig {
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| {
+            let mut path = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+            path.push("ibkr-toy");
+            path.push("stock-data");
+            path
+        })
+    }
+
+    pub fn contracts_dir(&self) -> PathBuf {
+        self.contracts_dir.clone().unwrap_or_else(|| {
+            let mut path = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+            path.push("ibkr-toy");
+            path.push("contracts");
+            path
+        })
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            ttl_millis: 24 * 60 * 60 * 1000,
+            contracts_dir: None,
+            contracts_ttl_millis: 7 * 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+/// Trading sessions deciding when cached data gets outdated.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct CalendarConfig {
+    /// Calendars by exchange, adding to or replacing the built-in ones.
+    pub exchanges: HashMap<String, ExchangeCalendar>,
+}
+
+/// Which positions of the portfolio are considered.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct PortfolioConfig {
+    /// Asset classes to consider, e.g. `STK`, `FUND` or `BOND`.
+    pub asset_classes: Vec<String>,
+
+    /// Considers positions that were sold entirely.
+    pub keep_zero_positions: bool,
+
+    /// Contracts always considered regardless of the other rules.
+    pub include_conids: Vec<i64>,
+
+    /// Contracts never considered, even if listed in `include-conids`.
+    pub exclude_conids: Vec<i64>,
+}
+
+impl Default for PortfolioConfig {
+    fn default() -> Self {
+        Self {
+            asset_classes: vec!["STK".into()],
+            keep_zero_positions: false,
+            include_conids: Vec::default(),
+            exclude_conids: Vec::default(),
+        }
+    }
+}
+
+/// How much each scoring factor counts in the ranking.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct RankingConfig {
+    /// Overrides the default weights of some factors.
+    pub weights: HashMap<ScoringFactor, f64>,
+}
+
+impl RankingConfig {
+    /// Whether any weighted factor is computed from the history, which is 0 by default.
+    pub fn needs_history(&self) -> bool {
+        self.weights
+            .iter()
+            .any(|(factor, weight)| factor.is_computed_from_history() && *weight != 0.0)
+    }
+}
+
+/// Which historical bars to download and where to keep them.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// How far back to download when nothing is stored yet, e.g. `1y` or `6m`.
+    pub period: String,
+
+    /// Duration of a bar, e.g. `1d` or `1h`.
+    pub bar: String,
+
+    /// Directory of the bar store. Defaults to `ibkr-toy/history` in the user data directory.
+    pub store_dir: Option<PathBuf>,
+}
+
+impl HistoryConfig {
+    pub fn store_dir(&self) -> PathBuf {
+        self.store_dir.clone().unwrap_or_else(|| {
+            let mut path = dirs::data_dir().unwrap_or_else(std::env::temp_dir);
+            path.push("ibkr-toy");
+            path.push("history");
+            path
+        })
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            period: "1y".into(),
+            bar: "1d".into(),
+            store_dir: None,
+        }
+    }
+}
+
+/// Stocks to rank besides the holdings, so that the advice may suggest new positions.
+#[derive(Deserialize, Default, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct UniverseConfig {
+    /// Names of IBKR watchlists.
+    pub watchlists: Vec<String>,
+
+    /// File with one `TICKER,CONID` per line.
+    pub file: Option<PathBuf>,
+}
+
+/// Market scanner of IBKR, see `iserver/scanner/params` for the possible values.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScannerConfig {
+    /// Instrument type, e.g. `STK`.
+    pub instrument: String,
+
+    /// Scan code, e.g. `HIGH_DIVIDEND_YIELD_IB` or `TOP_PERC_LOSE`.
+    pub r#type: String,
+
+    /// Location code, e.g. `STK.EU.IBIS` or `STK.US.MAJOR`.
+    pub location: String,
+
+    #[serde(default)]
+    pub filters: Vec<ScannerFilter>,
+}
+
+/// Filter of a market scanner, e.g. `marketCapAbove1e6` with `10000`.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScannerFilter {
+    pub code: String,
+    pub value: serde_json::Value,
+}
+
+/// Where past runs are recorded.
+#[derive(Deserialize, Default, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// Defaults to `ibkr-toy/runs` in the user data directory.
+    pub dir: Option<PathBuf>,
+}
+
+impl ArchiveConfig {
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| {
+            let mut path = dirs::data_dir().unwrap_or_else(std::env::temp_dir);
+            path.push("ibkr-toy");
+            path.push("runs");
+            path
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_gateway() {
+        // Given
+        let yaml = r#"
+gateway:
+  base-url: https://gateway.example.com/v1/api/
+  port: 5001
+  ca-certificate: /etc/ibkr/ca.pem
+"#;
+        let expected_config = Config {
+            gateway: GatewayConfig {
+                base_url: "https://gateway.example.com/v1/api/".into(),
+                port: Some(5001),
+                ca_certificate: Some("/etc/ibkr/ca.pem".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // When
+        let actual_config: Config = serde_yaml::from_str(yaml).unwrap();
+
+        // Then
+        assert_eq!(expected_config, actual_config);
+    }
+
+    #[test]
+    fn parse_ranking() {
+        // Given
+        let yaml = r#"
+ranking:
+  weights:
+    rsi14: 1.5
+    drawdown-from-52-week-high: 2
+    52-week-high: 0.5
+    snapshot-price-ema50-change: 1
+    price-sma20-change: 3
+"#;
+        let expected_weights: HashMap<_, _> = [
+            (ScoringFactor::Rsi14, 1.5),
+            (ScoringFactor::DrawdownFrom52WeekHigh, 2.0),
+            (ScoringFactor::High52Week, 0.5),
+            (ScoringFactor::SnapshotPriceEma50Change, 1.0),
+            (ScoringFactor::PriceSmaChange(20), 3.0),
+        ]
+        .into();
+
+        // When
+        let actual_config: Config = serde_yaml::from_str(yaml).unwrap();
+
+        // Then
+        assert_eq!(expected_weights, actual_config.ranking.weights);
+    }
+
+    #[test]
+    fn parse_calendar() {
+        // Given
+        let yaml = r#"
+calendar:
+  exchanges:
+    NASDAQ:
+      time-zone: America/New_York
+      close: "16:00:00"
+      holidays: [2024-12-25]
+"#;
+        let expected_calendar = ExchangeCalendar {
+            time_zone: chrono_tz::America::New_York,
+            close: chrono::NaiveTime::from_hms_opt(16, 0, 0).unwrap(),
+            holidays: vec![chrono::NaiveDate::from_ymd_opt(2024, 12, 25).unwrap()],
+        };
+
+        // When
+        let actual_config: Config = serde_yaml::from_str(yaml).unwrap();
+
+        // Then
+        assert_eq!(
+            expected_calendar,
+            actual_config.calendar.exchanges["NASDAQ"]
+        );
+    }
+
+    #[test]
+    fn parse_scanners() {
+        // Given
+        let yaml = r#"
+scanners:
+  xetra-dividends:
+    instrument: STK
+    type: HIGH_DIVIDEND_YIELD_IB
+    location: STK.EU.IBIS
+    filters:
+      - code: marketCapAbove1e6
+        value: 10000
+"#;
+        let expected_scanner = ScannerConfig {
+            instrument: "STK".into(),
+            r#type: "HIGH_DIVIDEND_YIELD_IB".into(),
+            location: "STK.EU.IBIS".into(),
+            filters: vec![ScannerFilter {
+                code: "marketCapAbove1e6".into(),
+                value: 10000.into(),
+            }],
+        };
+
+        // When
+        let actual_config: Config = serde_yaml::from_str(yaml).unwrap();
+
+        // Then
+        assert_eq!(expected_scanner, actual_config.scanners["xetra-dividends"]);
+    }
+
+    #[test_case::case(ScoringFactor::Rsi14,              1.0 => true  ; "Weighted indicator")]
+    #[test_case::case(ScoringFactor::PriceSmaChange(20), 1.0 => true  ; "Weighted moving average")]
+    #[test_case::case(ScoringFactor::Rsi14,              0.0 => false ; "Unweighted indicator")]
+    #[test_case::case(ScoringFactor::DividendYield,      1.0 => false ; "Snapshot field")]
+    fn needs_history(factor: ScoringFactor, weight: f64) -> bool {
+        let config = RankingConfig {
+            weights: [(factor, weight)].into(),
+        };
+        config.needs_history()
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let config = Config::load(Path::new("/nonexistent/ibkr-toy.yaml")).unwrap();
+        assert_eq!(Config::default(), config);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::CacheConfig;
+use crate::ibkr_client::ContractInfo;
+use crate::stock_data_cacher::write_private;
+use crate::stock_data_downloader::ContractId;
+use crate::versioned;
+use crate::versioned::Migration;
+use crate::versioned::Versioned;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[mockall_double::double]
+use crate::ibkr_client::IbkrClient;
+
+#[mockall_double::double]
+use crate::clock::Clock;
+
+/// Downloads the details of contracts, keeping them on disk because they hardly ever change.
+#[cfg_attr(test, derive(Default))]
+pub struct ContractDetailsDownloader {
+    ibkr_client: Rc<IbkrClient>,
+    cache_dir: PathBuf,
+    ttl: Duration,
+    clock: Clock,
+}
+
+#[mockall::automock]
+impl ContractDetailsDownloader {
+    pub fn new(ibkr_client: Rc<IbkrClient>, config: &CacheConfig) -> Self {
+        Self {
+            ibkr_client,
+            cache_dir: config.contracts_dir(),
+            ttl: Duration::from_millis(config.contracts_ttl_millis),
+            clock: Default::default(),
+        }
+    }
+
+    /// Contracts failing to download are left out with a warning.
+    pub async fn download(&self, conids: &[i64]) -> HashMap<ContractId, ContractDetails> {
+        let mut result = HashMap::default();
+        for conid in conids {
+            match self.fetch(*conid).await {
+                Ok(details) => {
+                    result.insert((*conid).into(), details);
+                }
+                Err(e) => eprintln!(
+                    "Warning: Failed to download the details of contract {}: {:#}",
+                    conid, e
+                ),
+            }
+        }
+        result
+    }
+}
+
+impl ContractDetailsDownloader {
+    async fn fetch(&self, conid: i64) -> anyhow::Result<ContractDetails> {
+        let cache_path = self.cache_path(conid);
+        if let Some(cached) = read_cache(&cache_path).await {
+            let age = self.clock.now() - cached.timestamp;
+            if !age.to_std().is_ok_and(|age| age >= self.ttl) {
+                return Ok(cached.details);
+            }
+        }
+
+        let details: ContractDetails = self.ibkr_client.contract_info(conid).await?.into();
+        if let Err(e) = self.write_cache(&cache_path, &details).await {
+            eprintln!("Warning: {:#}", e);
+        }
+        Ok(details)
+    }
+
+    async fn write_cache(&self, path: &Path, details: &ContractDetails) -> anyhow::Result<()> {
+        let cached = CachedContractDetails {
+            details: details.clone(),
+            timestamp: self.clock.now(),
+        };
+        let text = versioned::to_string(&cached)?;
+        write_private(&self.cache_dir, path, text.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn cache_path(&self, conid: i64) -> PathBuf {
+        self.cache_dir.join(format!("{}.json", conid))
+    }
+}
+
+/// Unreadable or incompatible caches count as missing.
+async fn read_cache(path: &Path) -> Option<CachedContractDetails> {
+    let text = tokio::fs::read_to_string(path).await.ok()?;
+    versioned::from_str(&text).ok()
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedContractDetails {
+    details: ContractDetails,
+    timestamp: DateTime<Utc>,
+}
+
+impl Versioned for CachedContractDetails {
+    const NAME: &'static str = "Contract details";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// What a contract is and where it trades.
+#[derive(Deserialize, Serialize, Default, PartialEq, Debug, Clone)]
+pub struct ContractDetails {
+    pub long_name: Option<String>,
+    pub exchange: Option<String>,
+    pub currency: Option<String>,
+    pub sector: Option<String>,
+    pub industry: Option<String>,
+}
+
+impl From<ContractInfo> for ContractDetails {
+    fn from(info: ContractInfo) -> Self {
+        Self {
+            long_name: info.company_name,
+            exchange: info.exchange,
+            currency: info.currency,
+            sector: info.industry,
+            industry: info.category,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::MockClock;
+    use mockall::predicate::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn build_cache_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "ibkr-toy-test-contracts-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn build_ibkr_client(times: usize) -> IbkrClient {
+        let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_contract_info()
+            .with(eq(1))
+            .times(times)
+            .returning(|_| {
+                let info = ContractInfo {
+                    company_name: Some("APPLE INC".into()),
+                    currency: Some("USD".into()),
+                    industry: Some("Technology".into()),
+                    category: Some("Computers".into()),
+                    ..Default::default()
+                };
+                Ok(info)
+            });
+        ibkr_client
+    }
+
+    /// Clock at each of the `seconds` in turn.
+    fn build_clock(seconds: &[i64]) -> MockClock {
+        let mut clock = MockClock::new();
+        let mut sequence = mockall::Sequence::new();
+        for second in seconds {
+            clock
+                .expect_now()
+                .times(1)
+                .in_sequence(&mut sequence)
+                .return_const(DateTime::from_timestamp(*second, 0).unwrap());
+        }
+        clock
+    }
+
+    #[tokio::test]
+    async fn download_once_per_contract() {
+        // Given
+        let cache_dir = build_cache_dir("once");
+        let downloader = ContractDetailsDownloader {
+            ibkr_client: build_ibkr_client(1).into(),
+            cache_dir: cache_dir.clone(),
+            ttl: TTL,
+            clock: build_clock(&[0, 59]),
+        };
+        let expected_details = ContractDetails {
+            long_name: Some("APPLE INC".into()),
+            currency: Some("USD".into()),
+            sector: Some("Technology".into()),
+            industry: Some("Computers".into()),
+            ..Default::default()
+        };
+
+        // When
+        let downloaded = downloader.download(&[1]).await;
+        let cached = downloader.download(&[1]).await;
+
+        // Then
+        assert_eq!(expected_details, downloaded[&1.into()]);
+        assert_eq!(expected_details, cached[&1.into()]);
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_again_after_ttl() {
+        // Given
+        let cache_dir = build_cache_dir("ttl");
+        let downloader = ContractDetailsDownloader {
+            ibkr_client: build_ibkr_client(2).into(),
+            cache_dir: cache_dir.clone(),
+            ttl: TTL,
+            clock: build_clock(&[0, 60, 60]),
+        };
+
+        // When
+        downloader.download(&[1]).await;
+        let downloaded = downloader.download(&[1]).await;
+
+        // Then
+        assert!(downloaded.contains_key(&1.into()));
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::bar_store::Bar;
+use crate::config::HistoryConfig;
+use crate::ibkr_client::HistoryBar;
+use chrono::DateTime;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+#[mockall_double::double]
+use crate::ibkr_client::IbkrClient;
+
+#[mockall_double::double]
+use crate::bar_store::BarStore;
+
+#[mockall_double::double]
+use crate::clock::Clock;
+
+/// Longest period in days the history endpoint accepts.
+const MAX_PERIOD_DAYS: i64 = 1000;
+
+/// Keeps the bar store up to date with the history on IBKR.
+#[cfg_attr(test, derive(Default))]
+pub struct HistoryDownloader {
+    ibkr_client: Rc<IbkrClient>,
+    bar_store: BarStore,
+    clock: Clock,
+    config: HistoryConfig,
+}
+
+#[mockall::automock]
+impl HistoryDownloader {
+    pub fn new(ibkr_client: Rc<IbkrClient>, config: HistoryConfig) -> Self {
+        Self {
+            ibkr_client,
+            bar_store: BarStore::new(config.store_dir()),
+            clock: Default::default(),
+            config,
+        }
+    }
+
+    /// Downloads the bars missing from the store.
+    ///
+    /// Contracts failing to update are skipped with a warning.
+    pub async fn download(&self, conids: &[i64]) {
+        for conid in conids {
+            if let Err(e) = self.update(*conid).await {
+                eprintln!(
+                    "Warning: Failed to update the history of contract {}: {:#}",
+                    conid, e
+                );
+            }
+        }
+    }
+}
+
+impl HistoryDownloader {
+    async fn update(&self, conid: i64) -> anyhow::Result<()> {
+        let bar = &self.config.bar;
+        let stored_bars = self.bar_store.load(conid, bar).await?;
+        let Some(period) = self.missing_period(&stored_bars) else {
+            return Ok(());
+        };
+        let history = self.ibkr_client.market_history(conid, &period, bar).await?;
+        let new_bars = history.data.iter().filter_map(convert_bar);
+        let bars = merge_bars(stored_bars, new_bars);
+        self.bar_store.save(conid, bar, &bars).await
+    }
+
+    /// Period to download so that the store catches up to today, or `None` if already done.
+    fn missing_period(&self, stored_bars: &[Bar]) -> Option<String> {
+        let Some(last_bar) = stored_bars.last() else {
+            return Some(self.config.period.clone());
+        };
+        let missing_days = (self.clock.now().date_naive() - last_bar.time.date_naive()).num_days();
+        // Overlap by one day so that the last stored bar gets completed
+        (missing_days > 0).then(|| format!("{}d", (missing_days + 1).min(MAX_PERIOD_DAYS)))
+    }
+}
+
+fn convert_bar(bar: &HistoryBar) -> Option<Bar> {
+    let result = Bar {
+        time: DateTime::from_timestamp_millis(bar.t)?,
+        open: bar.o,
+        high: bar.h,
+        low: bar.l,
+        close: bar.c,
+        volume: bar.v,
+    };
+    Some(result)
+}
+
+/// Combines the bars sorted by time, with the new ones replacing the stored ones of the same time.
+fn merge_bars(stored_bars: Vec<Bar>, new_bars: impl Iterator<Item = Bar>) -> Vec<Bar> {
+    let merged: BTreeMap<_, _> = stored_bars
+        .into_iter()
+        .chain(new_bars)
+        .map(|bar| (bar.time, bar))
+        .collect();
+    merged.into_values().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bar_store::MockBarStore;
+    use crate::clock::MockClock;
+    use crate::ibkr_client::MarketHistory;
+    use crate::ibkr_client::MockIbkrClient;
+    use mockall::predicate::*;
+
+    const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+    fn build_bar(day: i64, close: f64) -> Bar {
+        Bar {
+            time: DateTime::from_timestamp_millis(day * DAY_MILLIS).unwrap(),
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 0.0,
+        }
+    }
+
+    fn build_history_bar(day: i64, close: f64) -> HistoryBar {
+        HistoryBar {
+            t: day * DAY_MILLIS,
+            o: close,
+            h: close,
+            l: close,
+            c: close,
+            v: 0.0,
+        }
+    }
+
+    fn build_clock(day: i64) -> MockClock {
+        let mut clock = MockClock::new();
+        clock
+            .expect_now()
+            .return_const(DateTime::from_timestamp_millis(day * DAY_MILLIS).unwrap());
+        clock
+    }
+
+    #[tokio::test]
+    async fn download_missing_days() {
+        // Given
+        let mut bar_store = MockBarStore::default();
+        bar_store
+            .expect_load()
+            .with(eq(1), eq("1d"))
+            .return_once(|_, _| Ok(vec![build_bar(10, 1.0), build_bar(11, 2.0)]));
+        bar_store
+            .expect_save()
+            .withf(|conid, _, bars| {
+                *conid == 1
+                    && bars
+                        == [
+                            build_bar(10, 1.0),
+                            build_bar(11, 3.0),
+                            build_bar(12, 4.0),
+                            build_bar(13, 5.0),
+                        ]
+            })
+            .return_once(|_, _, _| Ok(()));
+        let mut ibkr_client = MockIbkrClient::default();
+        ibkr_client
+            .expect_market_history()
+            .with(eq(1), eq("3d"), eq("1d"))
+            .return_once(|_, _, _| {
+                let history = MarketHistory {
+                    data: vec![
+                        build_history_bar(11, 3.0),
+                        build_history_bar(12, 4.0),
+                        build_history_bar(13, 5.0),
+                    ],
+                };
+                Ok(history)
+            });
+        let downloader = HistoryDownloader {
+            ibkr_client: ibkr_client.into(),
+            bar_store,
+            clock: build_clock(13),
+            ..Default::default()
+        };
+
+        // When
+        downloader.download(&[1]).await;
+
+        // Then the merged bars are saved
+    }
+
+    #[tokio::test]
+    async fn download_full_period_into_empty_store() {
+        // Given
+        let mut bar_store = MockBarStore::default();
+        bar_store
+            .expect_load()
+            .return_once(|_, _| Ok(Vec::default()));
+        bar_store
+            .expect_save()
+            .withf(|_, _, bars| bars.is_empty())
+            .return_once(|_, _, _| Ok(()));
+        let mut ibkr_client = MockIbkrClient::default();
+        ibkr_client
+            .expect_market_history()
+            .with(eq(1), eq("1y"), eq("1d"))
+            .return_once(|_, _, _| Ok(Default::default()));
+        let downloader = HistoryDownloader {
+            ibkr_client: ibkr_client.into(),
+            bar_store,
+            clock: build_clock(13),
+            ..Default::default()
+        };
+
+        // When
+        downloader.download(&[1]).await;
+
+        // Then the full period is requested
+    }
+
+    #[tokio::test]
+    async fn skip_up_to_date_store() {
+        // Given
+        let mut bar_store = MockBarStore::default();
+        bar_store
+            .expect_load()
+            .return_once(|_, _| Ok(vec![build_bar(13, 1.0)]));
+        let downloader = HistoryDownloader {
+            bar_store,
+            clock: build_clock(13),
+            ..Default::default()
+        };
+
+        // When
+        downloader.download(&[1]).await;
+
+        // Then neither the gateway nor the store is called again
+    }
+}
@@ -1,75 +1,584 @@
+use crate::config::GatewayConfig;
+use crate::config::HttpConfig;
+use crate::config::RetryConfig;
+use crate::config::ScannerConfig;
+use crate::config::ScannerFilter;
+use crate::ibkr_error::excerpt;
+use crate::ibkr_error::IbkrError;
+use crate::request_pacer::RequestPacer;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use derive_more::Display;
+use derive_more::Error;
 use itertools::Itertools;
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::tls::Certificate;
 use reqwest::Client;
+use reqwest::Method;
+use reqwest::StatusCode;
+use reqwest::Url;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::task::JoinHandle;
 
 #[mockall_double::double]
 use crate::file_writer::FileWriter;
 
-#[derive(Default)]
+/// The gateway drops an idle session after about 5 minutes.
+const TICKLE_INTERVAL: Duration = Duration::from_secs(60);
+const REAUTHENTICATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const REAUTHENTICATION_POLL_ATTEMPTS: usize = 15;
+
 pub struct IbkrClient {
     file_writer: FileWriter,
+    base_url: Url,
+    connection: Connection,
+    retry: RetryConfig,
+    retry_count: AtomicUsize,
+    pacer: RequestPacer,
 }
 
 #[mockall::automock]
 impl IbkrClient {
+    pub fn new(config: &GatewayConfig) -> anyhow::Result<Self> {
+        let ca_certificates = if let Some(path) = &config.ca_certificate {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", path.display()))?
+        } else {
+            Vec::default()
+        };
+        let certificate_fingerprint = config
+            .certificate_fingerprint
+            .as_deref()
+            .map(parse_fingerprint)
+            .transpose()?;
+        if certificate_fingerprint.is_some() && config.insecure {
+            anyhow::bail!("Gateway cannot both pin a certificate fingerprint and be insecure");
+        }
+        if config.insecure {
+            eprintln!("Warning: TLS certificate of the gateway will not be verified");
+        }
+        let http_client = build_http_client(
+            &config.http,
+            ca_certificates,
+            certificate_fingerprint,
+            config.insecure,
+        )
+        .context("Failed to build HTTP client")?;
+
+        Ok(Self {
+            file_writer: Default::default(),
+            base_url: parse_base_url(config)?,
+            connection: Connection {
+                http_client,
+                read_timeout: Duration::from_millis(config.http.read_timeout_millis),
+            },
+            retry: config.retry.clone(),
+            retry_count: Default::default(),
+            pacer: Default::default(),
+        })
+    }
+
+    /// Makes sure the brokerage session is authenticated, reauthenticating it if needed.
+    pub async fn ensure_session(&self) -> Result<(), IbkrError> {
+        let status = self.auth_status().await?;
+        if check_auth_status(&status, &self.login_url())? {
+            return Ok(());
+        }
+
+        println!("Brokerage session is not authenticated, reauthenticating");
+        self.reauthenticate().await?;
+        for _ in 0..REAUTHENTICATION_POLL_ATTEMPTS {
+            tokio::time::sleep(REAUTHENTICATION_POLL_INTERVAL).await;
+            let status = self.auth_status().await?;
+            if check_auth_status(&status, &self.login_url())? {
+                return Ok(());
+            }
+        }
+        Err(IbkrError::NotAuthenticated {
+            reason: "Reauthentication timed out".into(),
+            login_url: self.login_url().into(),
+        })
+    }
+
+    /// Tickles the gateway periodically so that the session does not expire during a long run.
+    pub fn keep_session_alive(&self) -> SessionKeeper {
+        let connection = self.connection.clone();
+        let endpoint = self.endpoint_url("tickle");
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICKLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = connection.send(Method::POST, endpoint.clone(), None).await {
+                    eprintln!("Warning: Failed to keep the gateway session alive: {}", e);
+                }
+            }
+        });
+        SessionKeeper { task }
+    }
+
+    /// Number of requests retried so far.
+    pub fn retry_count(&self) -> usize {
+        self.retry_count.load(Ordering::Relaxed)
+    }
+
+    pub async fn auth_status(&self) -> Result<AuthStatus, IbkrError> {
+        self.fetch_json(Method::POST, "iserver/auth/status").await
+    }
+
+    pub async fn reauthenticate(&self) -> Result<(), IbkrError> {
+        self.fetch(Method::POST, "iserver/reauthenticate").await?;
+        Ok(())
+    }
+
     pub async fn market_snapshot(
         &self,
         conids: &[i64],
         fields: &[i32],
-    ) -> anyhow::Result<Vec<HashMap<String, Value>>> {
+    ) -> Result<Vec<HashMap<String, Value>>, IbkrError> {
         let conids_text = conids.iter().join(",");
         let fields_text = fields.iter().join(",");
         let endpoint = format!(
             "iserver/marketdata/snapshot?conids={}&fields={}",
             conids_text, fields_text
         );
-        let data = fetch(&endpoint).await?;
-        self.file_writer
-            .write(&write_path("ibkr-market-snapshot.json"), data.as_bytes())
+        let data = self.fetch(Method::GET, &endpoint).await?;
+        let dump_path = write_path("ibkr-market-snapshot.json");
+        if let Err(e) = self.file_writer.write(&dump_path, data.as_bytes()).await {
+            eprintln!("Warning: Failed to write {}: {}", dump_path.display(), e);
+        }
+        parse_json(&endpoint, &data)
+    }
+
+    /// Downloads the bars of a contract over `period` (e.g. `1y`), each lasting `bar` (e.g. `1d`).
+    pub async fn market_history(
+        &self,
+        conid: i64,
+        period: &str,
+        bar: &str,
+    ) -> Result<MarketHistory, IbkrError> {
+        let endpoint = format!(
+            "iserver/marketdata/history?conid={}&period={}&bar={}&outsideRth=false",
+            conid, period, bar
+        );
+        self.fetch_json(Method::GET, &endpoint).await
+    }
+
+    pub async fn contract_info(&self, conid: i64) -> Result<ContractInfo, IbkrError> {
+        let endpoint = format!("iserver/contract/{}/info", conid);
+        self.fetch_json(Method::GET, &endpoint).await
+    }
+
+    pub async fn watchlists(&self) -> Result<Watchlists, IbkrError> {
+        self.fetch_json(Method::GET, "iserver/watchlists?SC=USER_WATCHLIST")
+            .await
+    }
+
+    pub async fn watchlist(&self, id: &str) -> Result<Watchlist, IbkrError> {
+        let endpoint = format!("iserver/watchlist?id={}", id);
+        self.fetch_json(Method::GET, &endpoint).await
+    }
+
+    /// Runs a market scanner, returning at most 50 contracts.
+    pub async fn run_scanner(&self, scanner: &ScannerConfig) -> Result<ScannerResult, IbkrError> {
+        let endpoint = "iserver/scanner/run";
+        let request = ScannerRequest {
+            instrument: &scanner.instrument,
+            r#type: &scanner.r#type,
+            location: &scanner.location,
+            filter: &scanner.filters,
+        };
+        let body = serde_json::to_value(request).expect("Scanner request is serializable");
+        let data = self
+            .fetch_with_body(Method::POST, endpoint, Some(&body))
             .await?;
-        serde_json::from_str(&data).map_err(Into::into)
+        parse_json(endpoint, &data)
     }
 
-    pub async fn i_server_accounts(&self) -> anyhow::Result<IServerAccount> {
-        let data = fetch("iserver/accounts").await?;
-        serde_json::from_str(&data).map_err(Into::into)
+    pub async fn i_server_accounts(&self) -> Result<IServerAccount, IbkrError> {
+        self.fetch_json(Method::GET, "iserver/accounts").await
     }
 
-    pub async fn portfolio_accounts(&self) -> anyhow::Result<Vec<PortfolioAccount>> {
-        let data = fetch("portfolio/accounts").await?;
-        serde_json::from_str(&data).map_err(Into::into)
+    pub async fn portfolio_accounts(&self) -> Result<Vec<PortfolioAccount>, IbkrError> {
+        self.fetch_json(Method::GET, "portfolio/accounts").await
+    }
+
+    /// Makes the gateway read the positions of an account again instead of serving its cache.
+    pub async fn invalidate_portfolio(&self, account_id: &str) -> Result<(), IbkrError> {
+        let endpoint = format!("portfolio/{}/positions/invalidate", account_id);
+        self.fetch(Method::POST, &endpoint).await?;
+        Ok(())
     }
 
     pub async fn portfolio(
         &self,
         account_id: &str,
         page_index: usize,
-    ) -> anyhow::Result<Vec<PortfolioPosition>> {
+    ) -> Result<Vec<PortfolioPosition>, IbkrError> {
         let endpoint = format!("portfolio/{}/positions/{}", account_id, page_index);
-        let data = fetch(&endpoint).await?;
-        serde_json::from_str(&data).map_err(Into::into)
+        self.fetch_json(Method::GET, &endpoint).await
+    }
+}
+
+impl IbkrClient {
+    async fn fetch_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        endpoint: &str,
+    ) -> Result<T, IbkrError> {
+        let data = self.fetch(method, endpoint).await?;
+        parse_json(endpoint, &data)
+    }
+
+    async fn fetch(&self, method: Method, endpoint: &str) -> Result<String, IbkrError> {
+        self.fetch_with_body(method, endpoint, None).await
+    }
+
+    async fn fetch_with_body(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<&Value>,
+    ) -> Result<String, IbkrError> {
+        let endpoint_full = self.endpoint_url(endpoint);
+        let mut retries = 0;
+        let GatewayResponse { status, text, .. } = loop {
+            self.pacer.wait(endpoint).await;
+            let result = self
+                .connection
+                .send(method.clone(), endpoint_full.clone(), body)
+                .await;
+            // `Some` if the request can be retried, with the delay the gateway may have asked for
+            let retry_after = match &result {
+                Ok(response) if is_retryable_status(response.status, &method) => {
+                    Some(response.retry_after)
+                }
+                Err(e) if is_retryable_error(e, &method) => Some(None),
+                _ => None,
+            };
+            match retry_after {
+                Some(retry_after) if retries < self.retry.max_retries => {
+                    let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+                    let delay = retry_delay(&self.retry, retry_after, retries, jitter);
+                    retries += 1;
+                    self.retry_count.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                }
+                _ => break result?,
+            }
+        };
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(IbkrError::NotAuthenticated {
+                reason: "Not logged in to the gateway".into(),
+                login_url: self.login_url().into(),
+            });
+        }
+        if !status.is_success() {
+            return Err(IbkrError::Http {
+                endpoint: endpoint_full.into(),
+                status,
+                body: excerpt(&text),
+            });
+        }
+        Ok(text)
+    }
+
+    fn login_url(&self) -> Url {
+        let mut url = self.base_url.clone();
+        url.set_path("/");
+        url
+    }
+
+    fn endpoint_url(&self, endpoint: &str) -> Url {
+        self.base_url
+            .join(endpoint)
+            .expect("Relative endpoints always join onto the base URL")
+    }
+}
+
+/// Long-lived HTTP client shared by all requests, so that connections are pooled.
+#[derive(Clone)]
+struct Connection {
+    http_client: Client,
+    read_timeout: Duration,
+}
+
+impl Connection {
+    async fn send(
+        &self,
+        method: Method,
+        endpoint: Url,
+        body: Option<&Value>,
+    ) -> Result<GatewayResponse, IbkrError> {
+        let to_ibkr_error = |source: reqwest::Error| {
+            if let Some(reason) = tls_failure(&source) {
+                IbkrError::Tls { reason }
+            } else if source.is_connect() {
+                IbkrError::GatewayUnreachable {
+                    endpoint: endpoint.to_string(),
+                    source,
+                }
+            } else {
+                IbkrError::Request {
+                    endpoint: endpoint.to_string(),
+                    source,
+                }
+            }
+        };
+        let timed_out = |_| IbkrError::Timeout {
+            endpoint: endpoint.to_string(),
+            timeout: self.read_timeout,
+        };
+
+        let mut request = self
+            .http_client
+            .request(method, endpoint.clone())
+            .header("User-Agent", "IBKR Toy");
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let request = request.send();
+        let response = tokio::time::timeout(self.read_timeout, request)
+            .await
+            .map_err(timed_out)?
+            .map_err(to_ibkr_error)?;
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        let text = tokio::time::timeout(self.read_timeout, response.text())
+            .await
+            .map_err(timed_out)?
+            .map_err(to_ibkr_error)?;
+        Ok(GatewayResponse {
+            status,
+            text,
+            retry_after,
+        })
+    }
+}
+
+struct GatewayResponse {
+    status: StatusCode,
+    text: String,
+    retry_after: Option<Duration>,
+}
+
+/// Parses `Retry-After` as either delay-seconds or an HTTP-date, of which only the preferred
+/// format (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`) is understood.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past asks for no delay at all
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// A POST may have taken effect unless the gateway turned it away before handling it.
+fn is_retryable_status(status: StatusCode, method: &Method) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => method != Method::POST,
+        _ => false,
+    }
+}
+
+/// A POST may have taken effect before the connection broke, so it is not sent again.
+fn is_retryable_error(error: &IbkrError, method: &Method) -> bool {
+    method != Method::POST
+        && matches!(
+            error,
+            IbkrError::GatewayUnreachable { .. }
+                | IbkrError::Request { .. }
+                | IbkrError::Timeout { .. }
+        )
+}
+
+fn build_http_client(
+    config: &HttpConfig,
+    ca_certificates: Vec<Certificate>,
+    certificate_fingerprint: Option<Vec<u8>>,
+    insecure: bool,
+) -> reqwest::Result<Client> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_millis(config.connect_timeout_millis))
+        .timeout(Duration::from_millis(config.total_timeout_millis))
+        .pool_idle_timeout(Duration::from_millis(config.pool_idle_timeout_millis))
+        .pool_max_idle_per_host(config.pool_max_idle)
+        .tcp_keepalive(Duration::from_millis(config.tcp_keepalive_millis));
+    for certificate in ca_certificates {
+        builder = builder.add_root_certificate(certificate);
+    }
+    if let Some(fingerprint) = certificate_fingerprint {
+        // The pinned fingerprint replaces the chain validation, checked before anything is sent
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerifier { fingerprint }))
+            .with_no_client_auth();
+        builder = builder.use_preconfigured_tls(tls);
+    } else if insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder.build()
+}
+
+fn parse_json<T: DeserializeOwned>(endpoint: &str, data: &str) -> Result<T, IbkrError> {
+    serde_json::from_str(data).map_err(|source| IbkrError::MalformedResponse {
+        endpoint: endpoint.into(),
+        source,
+        body: excerpt(data),
+    })
+}
+
+/// Exponential backoff scaled by `jitter` so that concurrent retries spread out.
+/// Waits as long as the gateway asked for, but no longer than the maximum backoff.
+fn retry_delay(
+    config: &RetryConfig,
+    retry_after: Option<Duration>,
+    retries: u32,
+    jitter: f64,
+) -> Duration {
+    match retry_after {
+        Some(retry_after) => retry_after.min(Duration::from_millis(config.max_backoff_millis)),
+        None => backoff_delay(config, retries, jitter),
+    }
+}
+
+fn backoff_delay(config: &RetryConfig, retries: u32, jitter: f64) -> Duration {
+    let exponential = config
+        .initial_backoff_millis
+        .saturating_mul(2_u64.saturating_pow(retries))
+        .min(config.max_backoff_millis);
+    Duration::from_millis(exponential).mul_f64(jitter)
+}
+
+/// Returns whether the session is ready, or an error if it cannot be recovered by reauthenticating.
+fn check_auth_status(status: &AuthStatus, login_url: &Url) -> Result<bool, IbkrError> {
+    let reason = if status.authenticated {
+        return Ok(true);
+    } else if status.competing {
+        "Another session (e.g. TWS or the mobile app) is using the brokerage account"
+    } else if !status.connected {
+        "Gateway is not connected to IBKR"
+    } else {
+        return Ok(false);
+    };
+    Err(IbkrError::NotAuthenticated {
+        reason: reason.into(),
+        login_url: login_url.to_string(),
+    })
+}
+
+fn parse_base_url(config: &GatewayConfig) -> anyhow::Result<Url> {
+    let mut base_url = Url::parse(&config.base_url)
+        .with_context(|| format!("Invalid gateway URL {}", config.base_url))?;
+    if config.port.is_some() {
+        base_url
+            .set_port(config.port)
+            .map_err(|_| anyhow::anyhow!("Gateway URL {} cannot have a port", config.base_url))?;
+    }
+
+    // Otherwise `Url::join` would replace the last path segment
+    if !base_url.path().ends_with('/') {
+        let path = format!("{}/", base_url.path());
+        base_url.set_path(&path);
     }
+    Ok(base_url)
 }
 
-async fn fetch(endpoint: &str) -> anyhow::Result<String> {
-    let endpoint_full = format!("https://127.0.0.1:5000/v1/api/{}", endpoint);
-    let response = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?
-        .get(&endpoint_full)
-        .header("User-Agent", "IBKR Toy")
-        .send()
-        .await?;
-    let status = response.status();
-    let text = response.text().await?;
-    if !status.is_success() {
-        anyhow::bail!("REST endpoint {} error {}: {}", endpoint_full, status, text);
+fn parse_fingerprint(text: &str) -> anyhow::Result<Vec<u8>> {
+    let hex: String = text.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 {
+        anyhow::bail!("Certificate fingerprint must be a SHA-256 digest");
     }
-    Ok(text)
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .context("Certificate fingerprint is not in hex")
+}
+
+/// Trusts the gateway certificate if and only if its SHA-256 digest is the pinned one.
+struct PinnedCertificateVerifier {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::Other(Arc::new(FingerprintMismatch)),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "TLS certificate of the gateway does not match the pinned fingerprint")]
+struct FingerprintMismatch;
+
+/// Describes the TLS failure behind a request error, if any.
+///
+/// A failed handshake also counts as a connection error, so this is checked first.
+fn tls_failure(source: &reqwest::Error) -> Option<String> {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(source);
+    while let Some(error) = cause {
+        if let Some(error) = error.downcast_ref::<rustls::Error>() {
+            let reason = match error {
+                rustls::Error::InvalidCertificate(rustls::CertificateError::Other(other)) => {
+                    other.to_string()
+                }
+                _ => format!("TLS handshake with the gateway failed: {}", error),
+            };
+            return Some(reason);
+        }
+        if let Some(error) = error.downcast_ref::<native_tls::Error>() {
+            return Some(format!("TLS handshake with the gateway failed: {}", error));
+        }
+        // `std::io::Error` skips the error it wraps in its sources
+        cause = match error.downcast_ref::<std::io::Error>() {
+            Some(error) => error
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => error.source(),
+        };
+    }
+    None
 }
 
 fn write_path(name: &str) -> PathBuf {
@@ -78,6 +587,110 @@ fn write_path(name: &str) -> PathBuf {
     path
 }
 
+/// Aborts the keepalive task when dropped.
+pub struct SessionKeeper {
+    task: JoinHandle<()>,
+}
+
+impl Drop for SessionKeeper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AuthStatus {
+    pub authenticated: bool,
+    pub competing: bool,
+    pub connected: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MarketHistory {
+    pub data: Vec<HistoryBar>,
+}
+
+/// One bar of [MarketHistory], with the time in milliseconds since the Unix epoch.
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct HistoryBar {
+    pub t: i64,
+    pub o: f64,
+    pub h: f64,
+    pub l: f64,
+    pub c: f64,
+    #[serde(default)]
+    pub v: f64,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default)]
+pub struct ContractInfo {
+    pub company_name: Option<String>,
+    pub exchange: Option<String>,
+    pub currency: Option<String>,
+
+    /// Actually the sector, e.g. "Technology".
+    pub industry: Option<String>,
+
+    /// Actually the industry, e.g. "Computers".
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Watchlists {
+    pub data: WatchlistsData,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WatchlistsData {
+    pub user_lists: Vec<WatchlistSummary>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default)]
+pub struct WatchlistSummary {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Watchlist {
+    pub instruments: Vec<WatchlistInstrument>,
+}
+
+/// Row of a [Watchlist], either a contract or a header without any.
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default)]
+pub struct WatchlistInstrument {
+    pub conid: Option<i64>,
+    pub ticker: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ScannerRequest<'a> {
+    instrument: &'a str,
+    r#type: &'a str,
+    location: &'a str,
+    filter: &'a [ScannerFilter],
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ScannerResult {
+    pub contracts: Vec<ScannerContract>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ScannerContract {
+    pub con_id: i64,
+    pub symbol: String,
+}
+
 #[derive(Deserialize)]
 pub struct IServerAccount {
     pub accounts: Vec<String>,
@@ -96,4 +709,443 @@ pub struct PortfolioPosition {
     pub ticker: String,
     pub position: f64,
     pub assetClass: String,
+
+    // Amounts in `currency`
+    #[serde(default)]
+    pub mktPrice: f64,
+    #[serde(default)]
+    pub mktValue: f64,
+    #[serde(default)]
+    pub avgCost: f64,
+    #[serde(default)]
+    pub unrealizedPnl: f64,
+    #[serde(default)]
+    pub realizedPnl: f64,
+    #[serde(default)]
+    pub currency: String,
+}
+
+impl PortfolioPosition {
+    pub fn cost_basis(&self) -> f64 {
+        self.avgCost * self.position
+    }
+
+    /// Unrealized gain over the cost basis, or `None` without a cost basis.
+    pub fn unrealized_gain(&self) -> Option<f64> {
+        let cost_basis = self.cost_basis();
+        (cost_basis != 0.0).then(|| self.unrealizedPnl / cost_basis)
+    }
+
+    /// Adds up another position of the same contract, e.g. held in another account.
+    pub fn add(&mut self, other: &Self) {
+        let position = self.position + other.position;
+        if position != 0.0 {
+            self.avgCost = (self.cost_basis() + other.cost_basis()) / position;
+        }
+        self.position = position;
+        self.mktValue += other.mktValue;
+        self.unrealizedPnl += other.unrealizedPnl;
+        self.realizedPnl += other.realizedPnl;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tokio::io::AsyncRead;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[test_case::case("https://127.0.0.1:5000/v1/api/", None       => "https://127.0.0.1:5000/v1/api/portfolio/accounts" ; "Default")]
+    #[test_case::case("https://gateway.lan/v1/api",     Some(5001) => "https://gateway.lan:5001/v1/api/portfolio/accounts" ; "Custom port without trailing slash")]
+    fn parse_base_url(base_url: &str, port: Option<u16>) -> String {
+        let config = GatewayConfig {
+            base_url: base_url.into(),
+            port,
+            ..Default::default()
+        };
+        super::parse_base_url(&config)
+            .unwrap()
+            .join("portfolio/accounts")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn add_position() {
+        // Given
+        let mut position = PortfolioPosition {
+            position: 1.0,
+            avgCost: 10.0,
+            mktValue: 20.0,
+            unrealizedPnl: 10.0,
+            ..Default::default()
+        };
+        let other = PortfolioPosition {
+            position: 3.0,
+            avgCost: 30.0,
+            mktValue: 60.0,
+            unrealizedPnl: -30.0,
+            ..Default::default()
+        };
+
+        // When
+        position.add(&other);
+
+        // Then
+        assert_eq!(4.0, position.position);
+        assert_eq!(25.0, position.avgCost);
+        assert_eq!(80.0, position.mktValue);
+        assert_eq!(Some(-0.2), position.unrealized_gain());
+    }
+
+    #[test_case::case(true,  false, true  => true  ; "Authenticated")]
+    #[test_case::case(false, false, true  => false ; "Needs reauthentication")]
+    #[test_case::case(false, true,  true  => panics "Another session" ; "Competing")]
+    #[test_case::case(false, false, false => panics "not connected" ; "Disconnected")]
+    fn check_auth_status(authenticated: bool, competing: bool, connected: bool) -> bool {
+        let status = AuthStatus {
+            authenticated,
+            competing,
+            connected,
+        };
+        let login_url = Url::parse("https://127.0.0.1:5000/").unwrap();
+        super::check_auth_status(&status, &login_url).unwrap()
+    }
+
+    #[test_case::case(0, 1.0 => Duration::from_millis(500)  ; "First retry")]
+    #[test_case::case(2, 1.0 => Duration::from_millis(2000) ; "Third retry")]
+    #[test_case::case(9, 1.0 => Duration::from_secs(10)     ; "Capped")]
+    #[test_case::case(1, 0.5 => Duration::from_millis(500)  ; "With jitter")]
+    fn backoff_delay(retries: u32, jitter: f64) -> Duration {
+        super::backoff_delay(&Default::default(), retries, jitter)
+    }
+
+    #[test_case::case(Some(Duration::from_secs(3))   => Duration::from_secs(3)      ; "Retry-After")]
+    #[test_case::case(Some(Duration::from_secs(3600)) => Duration::from_secs(10)    ; "Retry-After capped")]
+    #[test_case::case(None                            => Duration::from_millis(500) ; "Without Retry-After")]
+    fn retry_delay(retry_after: Option<Duration>) -> Duration {
+        super::retry_delay(&Default::default(), retry_after, 0, 1.0)
+    }
+
+    #[test_case::case(StatusCode::SERVICE_UNAVAILABLE, Method::POST => true  ; "Unavailable POST")]
+    #[test_case::case(StatusCode::TOO_MANY_REQUESTS,   Method::POST => true  ; "Too many POST")]
+    #[test_case::case(StatusCode::GATEWAY_TIMEOUT,     Method::POST => false ; "Timed out POST")]
+    #[test_case::case(StatusCode::GATEWAY_TIMEOUT,     Method::GET  => true  ; "Timed out GET")]
+    #[test_case::case(StatusCode::NOT_FOUND,           Method::GET  => false ; "Not found")]
+    fn is_retryable_status(status: StatusCode, method: Method) -> bool {
+        super::is_retryable_status(status, &method)
+    }
+
+    #[test_case::case("3"                             => Some(Duration::from_secs(3)) ; "Delay seconds")]
+    #[test_case::case("Thu, 01 Jan 1970 00:01:00 GMT" => Some(Duration::from_secs(30)) ; "HTTP-date")]
+    #[test_case::case("Thu, 01 Jan 1970 00:00:00 GMT" => Some(Duration::ZERO)          ; "HTTP-date in the past")]
+    #[test_case::case("Thursday, 01-Jan-70 00:01:00 GMT" => None                       ; "Obsolete HTTP-date")]
+    #[test_case::case("soon"                          => None                          ; "Malformed")]
+    fn parse_retry_after(value: &str) -> Option<Duration> {
+        let now = DateTime::from_timestamp(30, 0).unwrap();
+        super::parse_retry_after(value, now)
+    }
+
+    #[test_case::case(Method::GET  => true  ; "GET")]
+    #[test_case::case(Method::POST => false ; "POST")]
+    fn is_retryable_timeout(method: Method) -> bool {
+        let error = IbkrError::Timeout {
+            endpoint: Default::default(),
+            timeout: Duration::from_secs(1),
+        };
+        super::is_retryable_error(&error, &method)
+    }
+
+    #[test]
+    fn new_with_http_config() {
+        let config = GatewayConfig {
+            http: HttpConfig {
+                pool_max_idle: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(IbkrClient::new(&config).is_ok());
+    }
+
+    #[test]
+    fn parse_fingerprint() {
+        let fingerprint = "AB:".repeat(31) + "CD";
+        let expected_bytes: Vec<u8> = [vec![0xAB; 31], vec![0xCD]].concat();
+        assert_eq!(
+            expected_bytes,
+            super::parse_fingerprint(&fingerprint).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_fingerprint_of_wrong_length() {
+        assert!(super::parse_fingerprint("AB:CD").is_err());
+    }
+
+    #[test]
+    fn new_with_fingerprint_and_insecure() {
+        let config = GatewayConfig {
+            certificate_fingerprint: Some("AB".repeat(32)),
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(IbkrClient::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_matches() {
+        // Given
+        let gateway = TestGateway::start("[]").await;
+        let client = gateway.client(Some(hex_fingerprint(&gateway.certificate)));
+
+        // When
+        let accounts = client.portfolio_accounts().await;
+
+        // Then
+        assert!(accounts.unwrap().is_empty());
+        assert!(!gateway.request().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_mismatches() {
+        // Given
+        let gateway = TestGateway::start("[]").await;
+        let client = gateway.client(Some("AB".repeat(32)));
+
+        // When
+        let accounts = client.portfolio_accounts().await;
+
+        // Then
+        assert!(matches!(accounts, Err(IbkrError::Tls { .. })));
+        assert!(gateway.request().is_empty());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        // Given
+        let gateway = TestGateway::start("[]").await;
+        let client = gateway.client(None);
+
+        // When
+        let accounts = client.portfolio_accounts().await;
+
+        // Then
+        assert!(matches!(accounts, Err(IbkrError::Tls { .. })));
+        assert!(gateway.request().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_scanner() {
+        // Given
+        let gateway = TestGateway::start(include_str!("../testdata/scanner-run.json")).await;
+        let client = gateway.client(Some(hex_fingerprint(&gateway.certificate)));
+        let scanner = ScannerConfig {
+            instrument: "STK".into(),
+            r#type: "HIGH_DIVIDEND_YIELD_IB".into(),
+            location: "STK.EU.IBIS".into(),
+            filters: vec![ScannerFilter {
+                code: "marketCapAbove1e6".into(),
+                value: json!(10000),
+            }],
+        };
+
+        // When
+        let result = client.run_scanner(&scanner).await.unwrap();
+
+        // Then
+        let request = gateway.request();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /v1/api/iserver/scanner/run HTTP/1.1\r\n"));
+        assert_eq!(
+            json!({
+                "instrument": "STK",
+                "type": "HIGH_DIVIDEND_YIELD_IB",
+                "location": "STK.EU.IBIS",
+                "filter": [{"code": "marketCapAbove1e6", "value": 10000}],
+            }),
+            serde_json::from_str::<serde_json::Value>(body).unwrap()
+        );
+        let tickers: Vec<_> = result
+            .contracts
+            .iter()
+            .map(|contract| (contract.con_id, contract.symbol.as_str()))
+            .collect();
+        assert_eq!(
+            vec![(14121, "BAS"), (14073, "ALV"), (14142, "MBG")],
+            tickers
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_unavailable_gateway() {
+        // Given
+        let gateway = TestGateway::serve(vec![
+            http_response("503 Service Unavailable", "Retry-After: 0\r\n", ""),
+            http_response("200 OK", "", "{}"),
+        ])
+        .await;
+        let client = gateway.client(Some(hex_fingerprint(&gateway.certificate)));
+
+        // When
+        let watchlists = client.watchlists().await;
+
+        // Then
+        assert!(watchlists.unwrap().data.user_lists.is_empty());
+        assert_eq!(2, gateway.request_count());
+    }
+
+    #[tokio::test]
+    async fn give_up_retrying_unavailable_gateway() {
+        // Given
+        let gateway = TestGateway::serve(vec![http_response(
+            "503 Service Unavailable",
+            "Retry-After: 0\r\n",
+            "",
+        )])
+        .await;
+        let client = gateway.client(Some(hex_fingerprint(&gateway.certificate)));
+
+        // When
+        let watchlists = client.watchlists().await;
+
+        // Then
+        assert!(matches!(
+            watchlists,
+            Err(IbkrError::Http {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..
+            })
+        ));
+        assert_eq!(4, gateway.request_count());
+    }
+
+    /// Gateway over TLS with the self-signed certificate in `testdata`.
+    struct TestGateway {
+        port: u16,
+        certificate: Vec<u8>,
+
+        /// Last HTTP request arrived, empty if none did.
+        request: Arc<Mutex<Vec<u8>>>,
+        request_count: Arc<AtomicUsize>,
+    }
+
+    impl TestGateway {
+        /// Answers `body` to any request.
+        async fn start(body: &'static str) -> Self {
+            Self::serve(vec![http_response("200 OK", "", body)]).await
+        }
+
+        /// Answers the `responses` in turn, then the last one to any further request.
+        async fn serve(responses: Vec<String>) -> Self {
+            let certificate =
+                rustls_pemfile::certs(&mut &include_bytes!("../testdata/gateway-cert.pem")[..])
+                    .unwrap()
+                    .remove(0);
+            let key = rustls_pemfile::pkcs8_private_keys(
+                &mut &include_bytes!("../testdata/gateway-key.pem")[..],
+            )
+            .unwrap()
+            .remove(0);
+            let tls = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![rustls::Certificate(certificate.clone())],
+                    rustls::PrivateKey(key),
+                )
+                .unwrap();
+            let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let request = Arc::new(Mutex::new(Vec::new()));
+            let received = request.clone();
+            let request_count = Arc::new(AtomicUsize::new(0));
+            let received_count = request_count.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        continue;
+                    };
+                    *received.lock().unwrap() = read_request(&mut stream).await;
+                    let index = received_count.fetch_add(1, Ordering::Relaxed);
+                    let response = &responses[index.min(responses.len() - 1)];
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+            Self {
+                port,
+                certificate,
+                request,
+                request_count,
+            }
+        }
+
+        fn request(&self) -> String {
+            String::from_utf8_lossy(&self.request.lock().unwrap()).into_owned()
+        }
+
+        fn request_count(&self) -> usize {
+            self.request_count.load(Ordering::Relaxed)
+        }
+
+        fn client(&self, certificate_fingerprint: Option<String>) -> IbkrClient {
+            let config = GatewayConfig {
+                base_url: format!("https://127.0.0.1:{}/v1/api/", self.port),
+                certificate_fingerprint,
+                ..Default::default()
+            };
+            IbkrClient::new(&config).unwrap()
+        }
+    }
+
+    /// HTTP response with the given status line and `headers`, each ending in CRLF.
+    fn http_response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+            status,
+            body.len(),
+            headers,
+            body
+        )
+    }
+
+    /// Reads the head and the body of an HTTP request, or what arrived before the stream ended.
+    async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Vec<u8> {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !is_complete_request(&request) {
+            match stream.read(&mut buffer).await {
+                Ok(length) if length > 0 => request.extend_from_slice(&buffer[..length]),
+                _ => break,
+            }
+        }
+        request
+    }
+
+    fn is_complete_request(request: &[u8]) -> bool {
+        let request = String::from_utf8_lossy(request);
+        let Some((head, body)) = request.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0);
+        body.len() >= content_length
+    }
+
+    fn hex_fingerprint(certificate: &[u8]) -> String {
+        Sha256::digest(certificate)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect()
+    }
 }
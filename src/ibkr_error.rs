@@ -0,0 +1,81 @@
+use derive_more::Display;
+use derive_more::Error;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// Response bodies are cut to this many characters in error messages.
+const BODY_EXCERPT_LENGTH: usize = 200;
+
+/// Failure of a request to the Client Portal Gateway.
+#[derive(Debug, Display, Error)]
+pub enum IbkrError {
+    /// Nothing listens at the gateway address.
+    #[display(fmt = "Cannot reach the gateway at {}: {}", endpoint, source)]
+    GatewayUnreachable {
+        endpoint: String,
+        source: reqwest::Error,
+    },
+
+    /// The request failed halfway, e.g. because of a timeout or a connection reset.
+    #[display(fmt = "Request to {} failed: {}", endpoint, source)]
+    Request {
+        endpoint: String,
+        source: reqwest::Error,
+    },
+
+    #[display(fmt = "Request to {} timed out after {:?}", endpoint, timeout)]
+    Timeout { endpoint: String, timeout: Duration },
+
+    #[display(fmt = "{}", reason)]
+    Tls { reason: String },
+
+    /// The brokerage session is gone and the user must log in through the gateway.
+    #[display(fmt = "{}, please log in again at {}", reason, login_url)]
+    NotAuthenticated { reason: String, login_url: String },
+
+    #[display(fmt = "REST endpoint {} error {}: {}", endpoint, status, body)]
+    Http {
+        endpoint: String,
+        status: StatusCode,
+        body: String,
+    },
+
+    #[display(
+        fmt = "Malformed response from {}: {}. Response: {}",
+        endpoint,
+        source,
+        body
+    )]
+    MalformedResponse {
+        endpoint: String,
+        source: serde_json::Error,
+        body: String,
+    },
+}
+
+/// Shortens a response body so that it fits in an error message.
+pub fn excerpt(body: &str) -> String {
+    if body.chars().count() <= BODY_EXCERPT_LENGTH {
+        body.into()
+    } else {
+        let excerpt: String = body.chars().take(BODY_EXCERPT_LENGTH).collect();
+        format!("{}...", excerpt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn excerpt_long_body() {
+        let body = "a".repeat(BODY_EXCERPT_LENGTH + 1);
+        let expected_excerpt = "a".repeat(BODY_EXCERPT_LENGTH) + "...";
+        assert_eq!(expected_excerpt, excerpt(&body));
+    }
+
+    #[test]
+    fn excerpt_short_body() {
+        assert_eq!("{}", excerpt("{}"));
+    }
+}
@@ -3,6 +3,7 @@ use crate::stock_ranker::Notional;
 use crate::stock_ranker::Score;
 use crate::stock_ranker::Ticker;
 use itertools::Itertools;
+use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
 
@@ -15,6 +16,7 @@ impl InvestAdvisor {
         &self,
         scores: &HashMap<Ticker, Score>,
         invest_num: usize,
+        accounts: &HashMap<Ticker, String>,
     ) -> Vec<InvestAdviceEntry> {
         let candidates: Vec<_> = scores
             .iter()
@@ -26,24 +28,38 @@ impl InvestAdvisor {
         let total_score = candidates.iter().map(|(_, score)| score.value).sum();
         candidates
             .into_iter()
-            .map(|(ticker, score)| self.build_entry(ticker, score, total_score))
+            .map(|(ticker, score)| {
+                // Only held stocks have an account
+                let account = accounts.get(ticker).cloned();
+                self.build_entry(ticker, account, score, total_score)
+            })
             .collect()
     }
 
-    fn build_entry(&self, ticker: &Ticker, score: &Score, total_score: f64) -> InvestAdviceEntry {
+    fn build_entry(
+        &self,
+        ticker: &Ticker,
+        account: Option<String>,
+        score: &Score,
+        total_score: f64,
+    ) -> InvestAdviceEntry {
         let percentage = Notional {
             value: score.value / total_score,
         };
         let percentage = self.arithmetic_renderer.render_percentage(&percentage);
         InvestAdviceEntry {
             ticker: ticker.to_string(),
+            new_position: account.is_none(),
+            account: account.unwrap_or_default(),
             percentage,
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct InvestAdviceEntry {
     ticker: String,
+    account: String,
+    new_position: bool,
     percentage: String,
 }
@@ -1,24 +1,59 @@
+// Real implementations are replaced by their mocks in tests
+#![cfg_attr(test, allow(dead_code))]
+
 mod arithmetic_renderer;
+mod bar_store;
 mod clock;
+mod config;
+mod contract_details_downloader;
 mod file_writer;
+mod history_downloader;
 mod ibkr_client;
+mod ibkr_error;
 mod invest_advisor;
+mod market_calendar;
+mod market_scanner;
+mod position_filter;
 mod report_renderer;
+mod request_pacer;
+mod run_archive;
 mod scoring_factor_extractor;
+mod snapshot_field;
+mod statement_importer;
 mod stock_candidates;
 mod stock_data_cacher;
 mod stock_data_downloader;
 mod stock_ranker;
 mod table_printer;
+mod technical_indicator;
 mod toy;
+mod universe_loader;
+mod versioned;
 
+use crate::config::Config;
+use crate::toy::Failure;
 use crate::toy::Toy;
 use clap::Parser;
+use std::process::ExitCode;
 use toy::Cli;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let args = Cli::parse();
-    Toy::new(args).run().await?;
-    Ok(())
+    if let Err(e) = run(args).await {
+        eprintln!("Error: {:?}", e);
+        let failure = Failure::of(&e);
+        if let Some(hint) = failure.hint() {
+            eprintln!();
+            eprintln!("{}", hint);
+        }
+        return failure.exit_code();
+    }
+    ExitCode::SUCCESS
+}
+
+async fn run(args: Cli) -> anyhow::Result<()> {
+    let config_path = args.config.clone().unwrap_or_else(Config::default_path);
+    let config = Config::load(&config_path)?;
+    Toy::new(args, config)?.run().await
 }
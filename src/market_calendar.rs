@@ -0,0 +1,156 @@
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::NaiveDate;
+use chrono::NaiveTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use chrono::Weekday;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// How many days to look ahead for a session, enough to get over any run of holidays.
+const MAX_DAYS_TO_NEXT_SESSION: usize = 30;
+
+/// Trading sessions of the exchanges.
+#[derive(Default)]
+pub struct MarketCalendar {
+    exchanges: HashMap<String, ExchangeCalendar>,
+}
+
+impl MarketCalendar {
+    /// Builds the calendar of well-known exchanges, overridden or extended by `exchanges`.
+    pub fn new(exchanges: &HashMap<String, ExchangeCalendar>) -> Self {
+        let mut result = Self {
+            exchanges: default_exchanges(),
+        };
+        result.exchanges.extend(exchanges.clone());
+        result
+    }
+
+    /// Earliest close of a session after `after` among the known `exchanges`.
+    ///
+    /// `None` if none of the exchanges is known.
+    pub fn next_close<'a>(
+        &self,
+        exchanges: impl IntoIterator<Item = &'a str>,
+        after: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        exchanges
+            .into_iter()
+            .filter_map(|exchange| self.exchanges.get(exchange))
+            .filter_map(|calendar| calendar.next_close(after))
+            .min()
+    }
+}
+
+/// Trading days and closing time of an exchange.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ExchangeCalendar {
+    pub time_zone: Tz,
+
+    /// Local closing time of a session, e.g. `16:00:00`.
+    pub close: NaiveTime,
+
+    /// Weekdays without a session.
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+}
+
+impl ExchangeCalendar {
+    fn new(time_zone: Tz, close_hour: u32, close_minute: u32) -> Self {
+        Self {
+            time_zone,
+            close: NaiveTime::from_hms_opt(close_hour, close_minute, 0)
+                .expect("Closing times are valid"),
+            holidays: Vec::default(),
+        }
+    }
+
+    fn next_close(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut date = after.with_timezone(&self.time_zone).date_naive();
+        for _ in 0..MAX_DAYS_TO_NEXT_SESSION {
+            if self.is_trading_day(date) {
+                let close = self
+                    .time_zone
+                    .from_local_datetime(&date.and_time(self.close))
+                    .earliest()?
+                    .with_timezone(&Utc);
+                if close > after {
+                    return Some(close);
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn is_trading_day(&self, date: NaiveDate) -> bool {
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&date)
+    }
+}
+
+fn default_exchanges() -> HashMap<String, ExchangeCalendar> {
+    let new_york = ExchangeCalendar::new(chrono_tz::America::New_York, 16, 0);
+    [
+        ("NYSE", new_york.clone()),
+        ("NASDAQ", new_york.clone()),
+        ("ARCA", new_york.clone()),
+        ("AMEX", new_york.clone()),
+        ("BATS", new_york),
+        (
+            "IBIS",
+            ExchangeCalendar::new(chrono_tz::Europe::Berlin, 17, 30),
+        ),
+        (
+            "LSE",
+            ExchangeCalendar::new(chrono_tz::Europe::London, 16, 30),
+        ),
+        (
+            "SEHK",
+            ExchangeCalendar::new(chrono_tz::Asia::Hong_Kong, 16, 0),
+        ),
+    ]
+    .into_iter()
+    .map(|(exchange, calendar)| (exchange.to_string(), calendar))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().into()
+    }
+
+    // 2024-03-08 is a Friday, New York is 5 hours behind UTC before 2024-03-10
+    #[test_case::case("2024-03-08T15:00:00Z" => "2024-03-08T21:00:00+00:00" ; "Before close")]
+    #[test_case::case("2024-03-08T22:00:00Z" => "2024-03-11T20:00:00+00:00" ; "Over the weekend into daylight saving time")]
+    #[test_case::case("2024-03-09T12:00:00Z" => "2024-03-11T20:00:00+00:00" ; "During the weekend")]
+    #[test_case::case("2024-03-11T21:00:00Z" => "2024-03-13T20:00:00+00:00" ; "Before a holiday")]
+    fn next_close(after: &str) -> String {
+        let mut nasdaq = ExchangeCalendar::new(chrono_tz::America::New_York, 16, 0);
+        nasdaq.holidays = vec![NaiveDate::from_ymd_opt(2024, 3, 12).unwrap()];
+        let calendar = MarketCalendar::new(&[("NASDAQ".to_string(), nasdaq)].into());
+        calendar
+            .next_close(["NASDAQ"], utc(after))
+            .unwrap()
+            .to_rfc3339()
+    }
+
+    #[test]
+    fn next_close_of_earliest_exchange() {
+        let calendar = MarketCalendar::new(&Default::default());
+        let next_close =
+            calendar.next_close(["NASDAQ", "IBIS", "UNKNOWN"], utc("2024-03-08T12:00:00Z"));
+        assert_eq!(Some(utc("2024-03-08T16:30:00Z")), next_close);
+    }
+
+    #[test]
+    fn next_close_of_unknown_exchange() {
+        let calendar = MarketCalendar::new(&Default::default());
+        assert_eq!(None, calendar.next_close(["UNKNOWN"], Utc::now()));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::ScannerConfig;
+use crate::universe_loader::UniverseEntry;
+use anyhow::Context;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+#[mockall_double::double]
+use crate::ibkr_client::IbkrClient;
+
+/// Discovers stocks through the market scanners defined in the config.
+pub struct MarketScanner {
+    ibkr_client: Rc<IbkrClient>,
+    scanners: HashMap<String, ScannerConfig>,
+}
+
+impl MarketScanner {
+    pub fn new(ibkr_client: Rc<IbkrClient>, scanners: HashMap<String, ScannerConfig>) -> Self {
+        Self {
+            ibkr_client,
+            scanners,
+        }
+    }
+
+    /// Stocks found by the named scanners, or by all of them if none is named, without duplicates.
+    pub async fn scan(&self, names: &[String]) -> anyhow::Result<Vec<UniverseEntry>> {
+        let names = if names.is_empty() {
+            self.scanners.keys().cloned().sorted_unstable().collect()
+        } else {
+            names.to_vec()
+        };
+        if names.is_empty() {
+            anyhow::bail!("No scanner configured");
+        }
+
+        let mut entries = Vec::default();
+        for name in names {
+            let scanner = self.scanners.get(&name).with_context(|| {
+                format!(
+                    "Scanner {} not found, configured ones are: {}",
+                    name,
+                    self.scanners.keys().sorted_unstable().join(", ")
+                )
+            })?;
+            let result = self
+                .ibkr_client
+                .run_scanner(scanner)
+                .await
+                .with_context(|| format!("Failed to run scanner {}", name))?;
+            println!("Scanner {} found {} stocks", name, result.contracts.len());
+            entries.extend(result.contracts.into_iter().map(|contract| UniverseEntry {
+                conid: contract.con_id,
+                ticker: contract.symbol,
+            }));
+        }
+        let mut conids = HashSet::new();
+        entries.retain(|entry| conids.insert(entry.conid));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ibkr_client::MockIbkrClient;
+    use crate::ibkr_client::ScannerResult;
+
+    const RECORDED_RESPONSE: &str = include_str!("../testdata/scanner-run.json");
+
+    fn build_scanner(r#type: &str) -> ScannerConfig {
+        ScannerConfig {
+            instrument: "STK".into(),
+            r#type: r#type.into(),
+            location: "STK.EU.IBIS".into(),
+            filters: Vec::default(),
+        }
+    }
+
+    fn build_entry(ticker: &str, conid: i64) -> UniverseEntry {
+        UniverseEntry {
+            conid,
+            ticker: ticker.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_all_without_duplicates() {
+        // Given
+        let mut ibkr_client = MockIbkrClient::default();
+        ibkr_client
+            .expect_run_scanner()
+            .times(2)
+            .returning(|_| Ok(serde_json::from_str::<ScannerResult>(RECORDED_RESPONSE).unwrap()));
+        let scanner = MarketScanner::new(
+            ibkr_client.into(),
+            [
+                ("dividends".into(), build_scanner("HIGH_DIVIDEND_YIELD_IB")),
+                ("losers".into(), build_scanner("TOP_PERC_LOSE")),
+            ]
+            .into(),
+        );
+
+        // When
+        let entries = scanner.scan(&[]).await.unwrap();
+
+        // Then
+        assert_eq!(
+            vec![
+                build_entry("BAS", 14121),
+                build_entry("ALV", 14073),
+                build_entry("MBG", 14142),
+            ],
+            entries
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_unknown_scanner() {
+        // Given
+        let scanner = MarketScanner::new(
+            MockIbkrClient::default().into(),
+            [("dividends".into(), build_scanner("HIGH_DIVIDEND_YIELD_IB"))].into(),
+        );
+
+        // When
+        let error = scanner.scan(&["losers".into()]).await.unwrap_err();
+
+        // Then
+        assert_eq!(
+            "Scanner losers not found, configured ones are: dividends",
+            error.to_string()
+        );
+    }
+}
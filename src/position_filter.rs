@@ -0,0 +1,116 @@
+use crate::config::PortfolioConfig;
+use crate::ibkr_client::PortfolioPosition;
+use derive_more::Display;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Decides which positions of the portfolio are considered.
+#[derive(Default)]
+pub struct PositionFilter {
+    config: PortfolioConfig,
+}
+
+impl PositionFilter {
+    pub fn new(config: PortfolioConfig) -> Self {
+        Self { config }
+    }
+
+    /// Splits the positions into the kept ones and the filtered out ones.
+    pub fn apply(
+        &self,
+        positions: Vec<PortfolioPosition>,
+    ) -> (Vec<PortfolioPosition>, Vec<FilteredPosition>) {
+        let mut kept = Vec::default();
+        let mut filtered = Vec::default();
+        for position in positions {
+            match self.check(&position) {
+                Some(reason) => filtered.push(FilteredPosition { position, reason }),
+                None => kept.push(position),
+            }
+        }
+        (kept, filtered)
+    }
+
+    fn check(&self, position: &PortfolioPosition) -> Option<FilterReason> {
+        if self.config.exclude_conids.contains(&position.conid) {
+            return Some(FilterReason::Excluded);
+        }
+        if self.config.include_conids.contains(&position.conid) {
+            return None;
+        }
+        if !self.config.asset_classes.contains(&position.assetClass) {
+            return Some(FilterReason::AssetClass(position.assetClass.clone()));
+        }
+        // IBKR still lists stocks sold recently
+        if position.position == 0.0 && !self.config.keep_zero_positions {
+            return Some(FilterReason::ZeroPosition);
+        }
+        None
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct FilteredPosition {
+    pub position: PortfolioPosition,
+    pub reason: FilterReason,
+}
+
+/// Why a position is left out.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Display)]
+pub enum FilterReason {
+    #[display(fmt = "Excluded in the config")]
+    Excluded,
+
+    #[display(fmt = "Asset class {} not allowed", _0)]
+    AssetClass(String),
+
+    #[display(fmt = "Zero position")]
+    ZeroPosition,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_position(conid: i64, asset_class: &str, position: f64) -> PortfolioPosition {
+        PortfolioPosition {
+            conid,
+            assetClass: asset_class.into(),
+            position,
+            ..Default::default()
+        }
+    }
+
+    #[test_case::case(build_position(1, "STK", 10.0) => None                                        ; "Stock")]
+    #[test_case::case(build_position(1, "STK", 0.0)  => Some(FilterReason::ZeroPosition)            ; "Sold")]
+    #[test_case::case(build_position(1, "CASH", 1.0) => Some(FilterReason::AssetClass("CASH".into())) ; "Forex")]
+    #[test_case::case(build_position(2, "STK", 10.0) => Some(FilterReason::Excluded)                ; "Excluded")]
+    #[test_case::case(build_position(3, "BOND", 0.0) => None                                        ; "Included")]
+    fn check(position: PortfolioPosition) -> Option<FilterReason> {
+        let filter = PositionFilter::new(PortfolioConfig {
+            exclude_conids: vec![2],
+            include_conids: vec![3],
+            ..Default::default()
+        });
+        filter.check(&position)
+    }
+
+    #[test]
+    fn apply() {
+        // Given
+        let filter = PositionFilter::default();
+        let stock = build_position(1, "STK", 10.0);
+        let sold = build_position(2, "STK", 0.0);
+        let expected_filtered = vec![FilteredPosition {
+            position: sold.clone(),
+            reason: FilterReason::ZeroPosition,
+        }];
+
+        // When
+        let (kept, filtered) = filter.apply(vec![stock.clone(), sold]);
+
+        // Then
+        assert_eq!(vec![stock], kept);
+        assert_eq!(expected_filtered, filtered);
+    }
+}
@@ -1,6 +1,14 @@
 use crate::arithmetic_renderer::ArithmeticRenderer;
+use crate::config::RankingConfig;
+use crate::contract_details_downloader::ContractDetails;
+use crate::ibkr_client::PortfolioPosition;
 use crate::scoring_factor_extractor::ScoringFactor;
+use crate::snapshot_field;
+use crate::snapshot_field::DisplayFormat;
+use crate::snapshot_field::SNAPSHOT_FIELDS;
 use crate::stock_candidates::StockCandidates;
+use crate::stock_data_downloader::MarketSnapshot;
+use crate::stock_data_downloader::StockData;
 use crate::stock_ranker::Notional;
 use crate::stock_ranker::Score;
 use crate::stock_ranker::Ticker;
@@ -9,26 +17,167 @@ use serde::Serialize;
 use std::collections::HashMap;
 
 pub struct ReportRenderer {
-    pub arithmetic_renderer: ArithmeticRenderer,
+    arithmetic_renderer: ArithmeticRenderer,
+
+    /// Weights configured, which decide the columns besides those requested by default.
+    weights: HashMap<ScoringFactor, f64>,
 }
 
 impl ReportRenderer {
+    pub fn new(config: &RankingConfig) -> Self {
+        Self {
+            arithmetic_renderer: ArithmeticRenderer,
+            weights: config.weights.clone(),
+        }
+    }
+
     pub fn render(
         &self,
+        stock_data: &StockData,
         candidates: &StockCandidates,
         scores: &HashMap<Ticker, Score>,
+        accounts: &HashMap<Ticker, String>,
     ) -> Vec<ReportEntry> {
+        let positions: HashMap<Ticker, &PortfolioPosition> = stock_data
+            .portfolio
+            .iter()
+            .map(|position| (position.ticker.as_str().into(), position))
+            .collect();
+        // Market values only add up within a currency
+        let mut currency_values: HashMap<&str, f64> = HashMap::new();
+        for position in &stock_data.portfolio {
+            *currency_values.entry(&position.currency).or_default() += position.mktValue;
+        }
+        let mixed_currencies = currency_values.len() > 1;
+        let conids: HashMap<Ticker, i64> = stock_data
+            .portfolio
+            .iter()
+            .map(|position| (position.ticker.as_str().into(), position.conid))
+            .chain(
+                stock_data
+                    .universe
+                    .iter()
+                    .map(|entry| (entry.ticker.as_str().into(), entry.conid)),
+            )
+            .collect();
+        let columns = self.columns(candidates);
         candidates
             .iter()
             .map(|(ticker, factors)| {
                 (
-                    ticker.to_string(),
+                    ticker,
                     factors,
                     scores.get(ticker).cloned().unwrap_or_default().value,
                 )
             })
             .sorted_unstable_by(|(_, _, x), (_, _, y)| y.total_cmp(x))
-            .map(|(ticker, factors, score)| self.render_entry(ticker, factors, score))
+            .map(|(ticker, factors, score)| {
+                let position = positions.get(ticker).copied();
+                let holding = Holding {
+                    account: accounts.get(ticker).cloned().unwrap_or_default(),
+                    contract: position
+                        .and_then(|position| stock_data.contracts.get(&position.conid.into())),
+                    position,
+                    currency_value: position
+                        .map(|position| currency_values[position.currency.as_str()])
+                        .unwrap_or_default(),
+                    mixed_currencies,
+                };
+                let snapshot = conids
+                    .get(ticker)
+                    .and_then(|conid| stock_data.market_snapshot.get(&(*conid).into()));
+                self.render_entry(
+                    ticker.to_string(),
+                    holding,
+                    snapshot,
+                    &columns,
+                    factors,
+                    score,
+                )
+            })
+            .collect()
+    }
+
+    /// Snapshot fields requested under the weights or downloaded for any stock, in the order of
+    /// [SNAPSHOT_FIELDS], followed by the weighted indicators computed from the history.
+    fn columns(&self, candidates: &StockCandidates) -> Vec<Column> {
+        let requested_fields = snapshot_field::requested_fields(&self.weights);
+        let fields = SNAPSHOT_FIELDS
+            .iter()
+            .filter(|field| {
+                requested_fields
+                    .iter()
+                    .any(|requested| requested.id == field.id)
+                    || candidates
+                        .iter()
+                        .any(|(_, factors)| factors.contains_key(&field.factor))
+            })
+            .map(|field| Column {
+                name: field.name.into(),
+                factor: field.factor,
+                format: field.format,
+            });
+        let indicators = self
+            .weights
+            .iter()
+            .filter(|(factor, weight)| factor.is_computed_from_history() && **weight != 0.0)
+            .map(|(factor, _)| Column {
+                name: factor.to_string(),
+                factor: *factor,
+                format: indicator_format(*factor),
+            })
+            .sorted_unstable_by(|x, y| x.name.cmp(&y.name));
+        fields.chain(indicators).collect()
+    }
+
+    /// Lists the incomplete snapshots of both the holdings and the universe.
+    pub fn render_incomplete_snapshots(
+        &self,
+        stock_data: &StockData,
+    ) -> Vec<IncompleteSnapshotEntry> {
+        let holdings = stock_data
+            .portfolio
+            .iter()
+            .map(|position| (position.conid, &position.ticker));
+        let universe = stock_data
+            .universe
+            .iter()
+            .map(|entry| (entry.conid, &entry.ticker));
+        holdings
+            .chain(universe)
+            .filter_map(|(conid, ticker)| {
+                stock_data
+                    .incomplete_snapshots
+                    .get(&conid.into())
+                    .map(|fields| IncompleteSnapshotEntry {
+                        ticker: ticker.clone(),
+                        missing_fields: fields
+                            .iter()
+                            .map(|field| {
+                                let name = snapshot_field::field_name(field.field_id);
+                                match &field.malformed_value {
+                                    Some(value) => format!("{} (malformed: {})", name, value),
+                                    None => name,
+                                }
+                            })
+                            .join(", "),
+                    })
+            })
+            .sorted_unstable_by(|x, y| x.ticker.cmp(&y.ticker))
+            .collect()
+    }
+
+    pub fn render_filtered_positions(&self, stock_data: &StockData) -> Vec<FilteredPositionEntry> {
+        stock_data
+            .filtered_positions
+            .iter()
+            .map(|filtered| FilteredPositionEntry {
+                ticker: filtered.position.ticker.clone(),
+                conid: filtered.position.conid,
+                asset_class: filtered.position.assetClass.clone(),
+                reason: filtered.reason.to_string(),
+            })
+            .sorted_unstable_by(|x, y| x.ticker.cmp(&y.ticker))
             .collect()
     }
 
@@ -39,60 +188,185 @@ impl ReportRenderer {
     fn render_entry(
         &self,
         ticker: String,
+        holding: Holding,
+        snapshot: Option<&MarketSnapshot>,
+        columns: &[Column],
         factors: &HashMap<ScoringFactor, Notional>,
         score: f64,
     ) -> ReportEntry {
         let none = "None".to_string();
+        let contract = holding.contract.cloned().unwrap_or_default();
+        let position = holding.position;
         ReportEntry {
             ticker,
-            score: self.render_score(score),
-            pe_ratio: factors.get(&ScoringFactor::PeRatio).map_or_else(
-                || none.clone(),
-                |notional| self.arithmetic_renderer.render_float(notional.value),
-            ),
-            dividend_yield: factors.get(&ScoringFactor::DividendYield).map_or_else(
-                || none.clone(),
-                |v| self.arithmetic_renderer.render_percentage(v),
-            ),
-            pema_20: factors.get(&ScoringFactor::PriceEma20Change).map_or_else(
+            account: holding.account,
+            new_position: position.is_none(),
+            currency: contract.currency.unwrap_or_default(),
+            sector: contract.sector.unwrap_or_default(),
+            weight: position
+                .filter(|_| holding.currency_value != 0.0)
+                .map_or_else(
+                    || none.clone(),
+                    |position| {
+                        let weight = position.mktValue / holding.currency_value;
+                        let weight = self.arithmetic_renderer.render_percentage(&weight.into());
+                        if holding.mixed_currencies {
+                            format!("{} of {}", weight, position.currency)
+                        } else {
+                            weight
+                        }
+                    },
+                ),
+            cost_basis: position.map_or_else(
                 || none.clone(),
-                |v| self.arithmetic_renderer.render_percentage(v),
+                |position| self.arithmetic_renderer.render_float(position.cost_basis()),
             ),
-            pema_200: factors.get(&ScoringFactor::PriceEma200Change).map_or_else(
-                || none.clone(),
-                |v| self.arithmetic_renderer.render_percentage(v),
+            unrealized_gain: position
+                .and_then(PortfolioPosition::unrealized_gain)
+                .map_or_else(
+                    || none.clone(),
+                    |gain| self.arithmetic_renderer.render_percentage(&gain.into()),
+                ),
+            score: self.render_score(score),
+            market_data: snapshot
+                .and_then(|snapshot| snapshot.availability)
+                .map(|availability| availability.to_string())
+                .unwrap_or_default(),
+            factors: FactorColumns(
+                columns
+                    .iter()
+                    .map(|column| {
+                        let value = factors.get(&column.factor).map_or_else(
+                            || none.clone(),
+                            |notional| {
+                                let value = self.render_field(column.format, notional);
+                                match snapshot
+                                    .and_then(|snapshot| snapshot.qualifiers.get(&column.factor))
+                                {
+                                    Some(qualifier) => format!("{} ({})", value, qualifier),
+                                    None => value,
+                                }
+                            },
+                        );
+                        (column.name.clone(), value)
+                    })
+                    .collect(),
             ),
         }
     }
+
+    fn render_field(&self, format: DisplayFormat, notional: &Notional) -> String {
+        match format {
+            DisplayFormat::Float => self.arithmetic_renderer.render_float(notional.value),
+            DisplayFormat::Percentage => self.arithmetic_renderer.render_percentage(notional),
+            DisplayFormat::SuffixedNumber => {
+                self.arithmetic_renderer.render_suffixed(notional.value)
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Default, PartialEq, Eq, Debug)]
 pub struct ReportEntry {
     ticker: String,
+    account: String,
+    new_position: bool,
+    currency: String,
+    sector: String,
+    weight: String,
+    cost_basis: String,
+    unrealized_gain: String,
     score: String,
-    pe_ratio: String,
-    dividend_yield: String,
-    pema_20: String,
-    pema_200: String,
+
+    /// Whether the snapshot is real-time or delayed.
+    market_data: String,
+
+    #[serde(flatten)]
+    factors: FactorColumns,
+}
+
+/// Columns of the scoring factors in the order of [ReportRenderer::columns], with their names.
+#[derive(Default, PartialEq, Eq, Debug)]
+struct FactorColumns(Vec<(String, String)>);
+
+impl Serialize for FactorColumns {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(name, value)| (name, value)))
+    }
+}
+
+/// Scoring factor shown in the report, named after the snapshot field or the factor.
+struct Column {
+    name: String,
+    factor: ScoringFactor,
+    format: DisplayFormat,
+}
+
+/// RSI and %B are scores of their own, the other indicators changes relative to the price.
+fn indicator_format(factor: ScoringFactor) -> DisplayFormat {
+    match factor {
+        ScoringFactor::Rsi14 | ScoringFactor::BollingerPercentB => DisplayFormat::Float,
+        _ => DisplayFormat::Percentage,
+    }
+}
+
+/// Where and how much of a stock is held.
+struct Holding<'a> {
+    account: String,
+    position: Option<&'a PortfolioPosition>,
+    contract: Option<&'a ContractDetails>,
+
+    /// Market value of the positions in the currency of this one.
+    currency_value: f64,
+
+    /// Whether the portfolio holds positions in more than one currency,
+    /// so that the weight is only within the currency.
+    mixed_currencies: bool,
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct IncompleteSnapshotEntry {
+    ticker: String,
+    missing_fields: String,
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct FilteredPositionEntry {
+    ticker: String,
+    conid: i64,
+    asset_class: String,
+    reason: String,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::position_filter::FilterReason;
+    use crate::position_filter::FilteredPosition;
+    use crate::snapshot_field::MarketDataAvailability;
+    use crate::snapshot_field::Qualifier;
+    use crate::stock_data_downloader::IncompleteField;
+    use crate::universe_loader::UniverseEntry;
 
     #[test]
     fn entries_sorted_by_score_descendingly() {
         // Given
-        let renderer = ReportRenderer {
-            arithmetic_renderer: ArithmeticRenderer,
-        };
+        let renderer = ReportRenderer::new(&Default::default());
         let candidates: StockCandidates =
             [("A", Default::default()), ("B", Default::default())].into();
         let scores: HashMap<_, _> = [("A".into(), 1.0.into()), ("B".into(), 2.0.into())].into();
         let expected_tickers = vec!["B".to_string(), "A".to_string()];
 
         // When
-        let actual_report = renderer.render(&candidates, &scores);
+        let actual_report = renderer.render(
+            &Default::default(),
+            &candidates,
+            &scores,
+            &Default::default(),
+        );
         let actual_tickers: Vec<_> = actual_report
             .into_iter()
             .map(|entry| entry.ticker)
@@ -101,4 +375,308 @@ mod test {
         // Then
         assert_eq!(expected_tickers, actual_tickers);
     }
+
+    #[test]
+    fn render_contract_details() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let stock_data = StockData {
+            portfolio: vec![PortfolioPosition {
+                conid: 1,
+                ticker: "A".into(),
+                ..Default::default()
+            }],
+            contracts: [(
+                1.into(),
+                ContractDetails {
+                    currency: Some("EUR".into()),
+                    sector: Some("Technology".into()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let candidates: StockCandidates = [("A", Default::default())].into();
+
+        // When
+        let actual_report = renderer.render(
+            &stock_data,
+            &candidates,
+            &Default::default(),
+            &Default::default(),
+        );
+
+        // Then
+        assert_eq!("EUR", actual_report[0].currency);
+        assert_eq!("Technology", actual_report[0].sector);
+    }
+
+    #[test]
+    fn render_position() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let stock_data = StockData {
+            portfolio: vec![
+                PortfolioPosition {
+                    ticker: "A".into(),
+                    position: 2.0,
+                    avgCost: 50.0,
+                    mktValue: 150.0,
+                    unrealizedPnl: 50.0,
+                    ..Default::default()
+                },
+                PortfolioPosition {
+                    ticker: "B".into(),
+                    mktValue: 50.0,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let candidates: StockCandidates =
+            [("A", Default::default()), ("C", Default::default())].into();
+        let scores = [("A".into(), 1.0.into())].into();
+
+        // When
+        let actual_report = renderer.render(&stock_data, &candidates, &scores, &Default::default());
+
+        // Then
+        assert_eq!("75%", actual_report[0].weight);
+        assert_eq!("100", actual_report[0].cost_basis);
+        assert_eq!("50%", actual_report[0].unrealized_gain);
+        assert!(!actual_report[0].new_position);
+        assert_eq!("None", actual_report[1].weight);
+        assert!(actual_report[1].new_position);
+    }
+
+    #[test]
+    fn render_weight_within_currency() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let position = |ticker: &str, currency: &str, value: f64| PortfolioPosition {
+            ticker: ticker.into(),
+            currency: currency.into(),
+            mktValue: value,
+            ..Default::default()
+        };
+        let stock_data = StockData {
+            portfolio: vec![
+                position("A", "USD", 300.0),
+                position("B", "USD", 100.0),
+                position("C", "EUR", 1000.0),
+            ],
+            ..Default::default()
+        };
+        let candidates: StockCandidates = [
+            ("A", Default::default()),
+            ("B", Default::default()),
+            ("C", Default::default()),
+        ]
+        .into();
+        let scores = [
+            ("A".into(), 3.0.into()),
+            ("B".into(), 2.0.into()),
+            ("C".into(), 1.0.into()),
+        ]
+        .into();
+
+        // When
+        let actual_report = renderer.render(&stock_data, &candidates, &scores, &Default::default());
+
+        // Then
+        assert_eq!("75% of USD", actual_report[0].weight);
+        assert_eq!("25% of USD", actual_report[1].weight);
+        assert_eq!("100% of EUR", actual_report[2].weight);
+    }
+
+    #[test]
+    fn render_snapshot_fields() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let candidates: StockCandidates = [(
+            "A",
+            [
+                (ScoringFactor::PeRatio, 12.5.into()),
+                (ScoringFactor::DividendYield, 0.03.into()),
+            ]
+            .into(),
+        )]
+        .into();
+
+        // When
+        let actual_report = renderer.render(
+            &Default::default(),
+            &candidates,
+            &Default::default(),
+            &Default::default(),
+        );
+        let actual_json = serde_json::to_value(&actual_report[0]).unwrap();
+
+        // Then
+        assert_eq!("12.5", actual_json["P/E"]);
+        assert_eq!("3%", actual_json["Dividend yield"]);
+        assert_eq!("None", actual_json["Price/EMA(20)"]);
+        assert!(actual_json.get("Market cap").is_none());
+    }
+
+    #[test]
+    fn render_weighted_indicators() {
+        // Given
+        let renderer = ReportRenderer::new(&RankingConfig {
+            weights: [
+                (ScoringFactor::PriceSmaChange(50), 1.0),
+                (ScoringFactor::Rsi14, 0.5),
+                (ScoringFactor::MacdHistogram, 0.0),
+            ]
+            .into(),
+        });
+        let candidates: StockCandidates = [(
+            "A",
+            [
+                (ScoringFactor::PriceSmaChange(50), (-0.04).into()),
+                (ScoringFactor::Rsi14, 28.5.into()),
+                (ScoringFactor::MacdHistogram, 0.01.into()),
+            ]
+            .into(),
+        )]
+        .into();
+
+        // When
+        let actual_report = renderer.render(
+            &Default::default(),
+            &candidates,
+            &Default::default(),
+            &Default::default(),
+        );
+        let actual_json = serde_json::to_value(&actual_report[0]).unwrap();
+
+        // Then
+        assert_eq!("-4%", actual_json["price-sma50-change"]);
+        assert_eq!("28.5", actual_json["rsi14"]);
+        assert!(actual_json.get("macd-histogram").is_none());
+    }
+
+    #[test]
+    fn render_qualified_snapshot() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let stock_data = StockData {
+            universe: vec![UniverseEntry {
+                conid: 1,
+                ticker: "A".into(),
+            }],
+            market_snapshot: [(
+                1.into(),
+                MarketSnapshot {
+                    conid: 1,
+                    qualifiers: [(ScoringFactor::LastPrice, Qualifier::Close)].into(),
+                    availability: Some(MarketDataAvailability::Delayed),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let candidates: StockCandidates =
+            [("A", [(ScoringFactor::LastPrice, 123.5.into())].into())].into();
+
+        // When
+        let actual_report = renderer.render(
+            &stock_data,
+            &candidates,
+            &Default::default(),
+            &Default::default(),
+        );
+        let actual_json = serde_json::to_value(&actual_report[0]).unwrap();
+
+        // Then
+        assert_eq!("Delayed", actual_json["market_data"]);
+        assert_eq!("123.5 (close)", actual_json["Last price"]);
+    }
+
+    #[test]
+    fn render_incomplete_snapshots() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let stock_data = StockData {
+            portfolio: vec![
+                PortfolioPosition {
+                    conid: 1,
+                    ticker: "A".into(),
+                    ..Default::default()
+                },
+                PortfolioPosition {
+                    conid: 2,
+                    ticker: "B".into(),
+                    ..Default::default()
+                },
+            ],
+            universe: vec![UniverseEntry {
+                conid: 3,
+                ticker: "C".into(),
+            }],
+            incomplete_snapshots: [
+                (
+                    2.into(),
+                    vec![
+                        IncompleteField::missing(7290),
+                        IncompleteField {
+                            field_id: 7287,
+                            malformed_value: Some(r#""abc""#.into()),
+                        },
+                    ],
+                ),
+                (3.into(), vec![IncompleteField::missing(7290)]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let expected_entries = vec![
+            IncompleteSnapshotEntry {
+                ticker: "B".into(),
+                missing_fields: r#"P/E, Dividend yield (malformed: "abc")"#.into(),
+            },
+            IncompleteSnapshotEntry {
+                ticker: "C".into(),
+                missing_fields: "P/E".into(),
+            },
+        ];
+
+        // When
+        let actual_entries = renderer.render_incomplete_snapshots(&stock_data);
+
+        // Then
+        assert_eq!(expected_entries, actual_entries);
+    }
+
+    #[test]
+    fn render_filtered_positions() {
+        // Given
+        let renderer = ReportRenderer::new(&Default::default());
+        let stock_data = StockData {
+            filtered_positions: vec![FilteredPosition {
+                position: PortfolioPosition {
+                    conid: 1,
+                    ticker: "CZK".into(),
+                    assetClass: "CASH".into(),
+                    ..Default::default()
+                },
+                reason: FilterReason::AssetClass("CASH".into()),
+            }],
+            ..Default::default()
+        };
+        let expected_entries = vec![FilteredPositionEntry {
+            ticker: "CZK".into(),
+            conid: 1,
+            asset_class: "CASH".into(),
+            reason: "Asset class CASH not allowed".into(),
+        }];
+
+        // When
+        let actual_entries = renderer.render_filtered_positions(&stock_data);
+
+        // Then
+        assert_eq!(expected_entries, actual_entries);
+    }
 }
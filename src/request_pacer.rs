@@ -0,0 +1,102 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Minimum interval between any two requests to the gateway, i.e. at most 10 per second.
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pacing limits of individual endpoints, matched by prefix.
+///
+/// See <https://www.interactivebrokers.com/campus/ibkr-api-page/cpapi-v1/#pacing-limitations>.
+const ENDPOINT_INTERVALS: [(&str, Duration); 9] = [
+    ("iserver/marketdata/snapshot", Duration::from_millis(100)),
+    ("iserver/marketdata/history", Duration::from_millis(200)),
+    ("iserver/scanner/params", Duration::from_secs(15 * 60)),
+    ("iserver/scanner/run", Duration::from_secs(1)),
+    ("iserver/trades", Duration::from_secs(5)),
+    ("portfolio/accounts", Duration::from_secs(5)),
+    ("portfolio/subaccounts", Duration::from_secs(5)),
+    ("sso/validate", Duration::from_secs(60)),
+    ("tickle", Duration::from_secs(1)),
+];
+
+/// Queues requests so that they stay within the pacing limits of the Client Portal API.
+pub struct RequestPacer {
+    global: Slot,
+    endpoints: Vec<(&'static str, Slot)>,
+}
+
+impl Default for RequestPacer {
+    fn default() -> Self {
+        Self::new(GLOBAL_INTERVAL, &ENDPOINT_INTERVALS)
+    }
+}
+
+impl RequestPacer {
+    fn new(global_interval: Duration, endpoint_intervals: &[(&'static str, Duration)]) -> Self {
+        Self {
+            global: Slot::new(global_interval),
+            endpoints: endpoint_intervals
+                .iter()
+                .map(|(prefix, interval)| (*prefix, Slot::new(*interval)))
+                .collect(),
+        }
+    }
+
+    /// Waits until a request to `endpoint` is allowed.
+    pub async fn wait(&self, endpoint: &str) {
+        if let Some((_, slot)) = self
+            .endpoints
+            .iter()
+            .find(|(prefix, _)| endpoint.starts_with(prefix))
+        {
+            slot.wait().await;
+        }
+        self.global.wait().await;
+    }
+}
+
+struct Slot {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Slot {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Default::default(),
+        }
+    }
+
+    async fn wait(&self) {
+        // Holding the lock while sleeping makes the waiters line up
+        let mut next = self.next.lock().await;
+        if let Some(next) = *next {
+            tokio::time::sleep_until(next).await;
+        }
+        *next = Some(Instant::now() + self.interval);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_endpoint_interval() {
+        // Given
+        let interval = Duration::from_millis(50);
+        let pacer = RequestPacer::new(Duration::ZERO, &[("slow", interval)]);
+        let start = Instant::now();
+
+        // When
+        for _ in 0..3 {
+            pacer.wait("slow/endpoint").await;
+        }
+        pacer.wait("fast/endpoint").await;
+
+        // Then
+        assert_eq!(start.elapsed(), interval * 2);
+    }
+}
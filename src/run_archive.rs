@@ -0,0 +1,337 @@
+use crate::invest_advisor::InvestAdviceEntry;
+use crate::scoring_factor_extractor::ScoringFactor;
+use crate::stock_candidates::StockCandidates;
+use crate::stock_data_downloader::StockData;
+use crate::stock_ranker::Score;
+use crate::stock_ranker::Ticker;
+use crate::versioned;
+use crate::versioned::Migration;
+use crate::versioned::Versioned;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+/// Everything a report was generated from, enough to render it again.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Run {
+    pub timestamp: DateTime<Utc>,
+
+    /// Accounts ranked together, separated by commas.
+    pub account: String,
+
+    pub stock_data: StockData,
+    pub factors: HashMap<String, HashMap<ScoringFactor, f64>>,
+    pub scores: HashMap<String, f64>,
+
+    /// Accounts holding each ticker.
+    pub accounts: HashMap<String, String>,
+
+    pub advice: Vec<InvestAdviceEntry>,
+}
+
+impl Run {
+    pub fn new(
+        timestamp: DateTime<Utc>,
+        account: String,
+        stock_data: StockData,
+        candidates: &StockCandidates,
+        scores: &HashMap<Ticker, Score>,
+        accounts: &HashMap<Ticker, String>,
+        advice: Vec<InvestAdviceEntry>,
+    ) -> Self {
+        let factors = candidates
+            .iter()
+            .map(|(ticker, factors)| {
+                let factors = factors
+                    .iter()
+                    .map(|(factor, notional)| (*factor, notional.value))
+                    .collect();
+                (ticker.to_string(), factors)
+            })
+            .collect();
+        Self {
+            timestamp,
+            account,
+            stock_data,
+            factors,
+            scores: scores
+                .iter()
+                .map(|(ticker, score)| (ticker.to_string(), score.value))
+                .collect(),
+            accounts: accounts
+                .iter()
+                .map(|(ticker, account)| (ticker.to_string(), account.clone()))
+                .collect(),
+            advice,
+        }
+    }
+
+    pub fn candidates(&self) -> StockCandidates {
+        let mut candidates = StockCandidates::default();
+        for (ticker, factors) in &self.factors {
+            for (factor, value) in factors {
+                candidates.add_candidate(ticker.as_str().into(), *factor, (*value).into());
+            }
+        }
+        candidates
+    }
+
+    pub fn scores(&self) -> HashMap<Ticker, Score> {
+        self.scores
+            .iter()
+            .map(|(ticker, score)| (ticker.as_str().into(), (*score).into()))
+            .collect()
+    }
+
+    pub fn accounts(&self) -> HashMap<Ticker, String> {
+        self.accounts
+            .iter()
+            .map(|(ticker, account)| (ticker.as_str().into(), account.clone()))
+            .collect()
+    }
+}
+
+impl Versioned for Run {
+    const NAME: &'static str = "Run";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// Append-only record of the runs, in one JSON Lines file per month.
+pub struct RunArchive {
+    dir: PathBuf,
+}
+
+impl RunArchive {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub async fn append(&self, run: &Run) -> anyhow::Result<()> {
+        let mut dir_builder = tokio::fs::DirBuilder::new();
+        dir_builder.recursive(true);
+        #[cfg(unix)]
+        dir_builder.mode(0o700);
+        dir_builder
+            .create(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        let mut line = versioned::to_string(run).context("Failed to serialize the run")?;
+        line.push('\n');
+        let path = self.segment_path(run.timestamp);
+        let mut options = tokio::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Loads all runs from the oldest to the latest.
+    ///
+    /// Lines that cannot be parsed, e.g. one cut off by a crash, are skipped with a warning.
+    pub async fn runs(&self) -> anyhow::Result<Vec<Run>> {
+        let mut runs = Vec::default();
+        for path in self.segment_paths().await? {
+            let text = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for (index, line) in text.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match versioned::from_str(line) {
+                    Ok(run) => runs.push(run),
+                    Err(e) => eprintln!(
+                        "Warning: Skipping line {} of {}: {:#}",
+                        index + 1,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+        runs.sort_by_key(|run: &Run| run.timestamp);
+        Ok(runs)
+    }
+
+    /// Loads the run numbered from 1 as listed by [`Self::summarize`].
+    pub async fn run(&self, number: usize) -> anyhow::Result<Run> {
+        let mut runs = self.runs().await?;
+        let count = runs.len();
+        if number == 0 || number > count {
+            anyhow::bail!("Run {} not found, there are {} runs", number, count);
+        }
+        Ok(runs.swap_remove(number - 1))
+    }
+
+    pub async fn summarize(&self) -> anyhow::Result<Vec<RunEntry>> {
+        let entries = self
+            .runs()
+            .await?
+            .into_iter()
+            .enumerate()
+            .map(|(index, run)| RunEntry {
+                run: index + 1,
+                timestamp: run.timestamp.to_rfc3339(),
+                account: run.account,
+                stocks: run.factors.len(),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Value of a scoring factor of a ticker in every run it was computed.
+    pub async fn factor_history(
+        &self,
+        ticker: &str,
+        factor: ScoringFactor,
+    ) -> anyhow::Result<Vec<FactorPoint>> {
+        let points = self
+            .runs()
+            .await?
+            .into_iter()
+            .filter_map(|run| {
+                let value = *run.factors.get(ticker)?.get(&factor)?;
+                Some(FactorPoint {
+                    timestamp: run.timestamp.to_rfc3339(),
+                    account: run.account,
+                    value,
+                })
+            })
+            .collect();
+        Ok(points)
+    }
+}
+
+impl RunArchive {
+    fn segment_path(&self, timestamp: DateTime<Utc>) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}",
+            timestamp.format("%Y-%m"),
+            SEGMENT_EXTENSION
+        ))
+    }
+
+    async fn segment_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::default();
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(paths),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()))
+            }
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort_unstable();
+        Ok(paths)
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct RunEntry {
+    run: usize,
+    timestamp: String,
+    account: String,
+    stocks: usize,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct FactorPoint {
+    timestamp: String,
+    account: String,
+    value: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_run(timestamp: i64, account: &str, rsi: f64) -> Run {
+        let candidates =
+            StockCandidates::from([("AAPL", [(ScoringFactor::Rsi14, rsi.into())].into())]);
+        let scores = [("AAPL".into(), 1.0.into())].into();
+        let accounts = [("AAPL".into(), account.to_string())].into();
+        Run::new(
+            DateTime::from_timestamp(timestamp, 0).unwrap(),
+            account.into(),
+            StockData::default(),
+            &candidates,
+            &scores,
+            &accounts,
+            Vec::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn append_and_query() {
+        // Given
+        let dir = std::env::temp_dir().join(format!("ibkr-toy-test-runs-{}", std::process::id()));
+        let archive = RunArchive::new(dir.clone());
+        let february = 86400 * 40;
+
+        // When
+        archive
+            .append(&build_run(february, "U1", 30.0))
+            .await
+            .unwrap();
+        archive.append(&build_run(0, "U1, U2", 70.0)).await.unwrap();
+        archive.append(&build_run(60, "U1", 50.0)).await.unwrap();
+        tokio::fs::write(dir.join("1970-03.jsonl"), "{\n")
+            .await
+            .unwrap();
+        let summary = archive.summarize().await.unwrap();
+        let factor_history = archive
+            .factor_history("AAPL", ScoringFactor::Rsi14)
+            .await
+            .unwrap();
+        let run = archive.run(3).await.unwrap();
+
+        // Then
+        assert_eq!(3, summary.len());
+        assert_eq!("U1, U2", summary[0].account);
+        assert_eq!(
+            vec![70.0, 50.0, 30.0],
+            factor_history
+                .iter()
+                .map(|point| point.value)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(build_run(february, "U1", 30.0), run);
+        assert!(archive.run(4).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore_ranking() {
+        // Given
+        let run = build_run(0, "U1", 30.0);
+
+        // When
+        let candidates = run.candidates();
+        let scores = run.scores();
+
+        // Then
+        let (ticker, factors) = candidates.iter().next().unwrap();
+        assert_eq!(Ticker::from("AAPL"), *ticker);
+        assert_eq!(30.0, factors[&ScoringFactor::Rsi14].value);
+        assert_eq!(1.0, scores[&"AAPL".into()].value);
+    }
+}
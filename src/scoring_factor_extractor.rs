@@ -1,69 +1,503 @@
+use crate::bar_store::Bar;
+use crate::config::HistoryConfig;
+use crate::config::RankingConfig;
+use crate::snapshot_field::SNAPSHOT_FIELDS;
 use crate::stock_candidates::StockCandidates;
 use crate::stock_data_downloader::StockData;
 use crate::stock_ranker::Ticker;
+use crate::technical_indicator;
+use crate::technical_indicator::TRADING_DAYS_PER_YEAR;
+use derive_more::Display;
+use derive_more::Error;
+use itertools::Itertools;
 use serde::Deserialize;
+use serde::Serialize;
+use std::str::FromStr;
 
-pub struct ScoringFactorExtractor;
+#[mockall_double::double]
+use crate::bar_store::BarStore;
+
+/// Moving averages computed from the history even if not weighted in the config.
+const DEFAULT_MOVING_AVERAGES: [ScoringFactor; 3] = [
+    ScoringFactor::PriceSmaChange(50),
+    ScoringFactor::PriceSmaChange(200),
+    ScoringFactor::PriceEmaChange(50),
+];
+
+#[cfg_attr(test, derive(Default))]
+pub struct ScoringFactorExtractor {
+    /// [ScoringFactor::PriceSmaChange] and [ScoringFactor::PriceEmaChange] of each length to compute.
+    moving_averages: Vec<ScoringFactor>,
+
+    /// Source of the bars to compute the technical indicators from.
+    bar_store: BarStore,
+
+    /// Duration of the stored bars.
+    bar: String,
+
+    /// Whether any weighted factor is computed from the bars, which are not read otherwise.
+    read_history: bool,
+}
 
 impl ScoringFactorExtractor {
-    pub fn extract_scoring_factors(&self, stock_data: &StockData) -> StockCandidates {
-        let mut candidates = StockCandidates::default();
-        for position in &stock_data.portfolio {
-            let conid = position.conid.into();
-            let ticker: Ticker = position.ticker.as_str().into();
-
-            if let Some(snapshot) = stock_data.market_snapshot.get(&conid) {
-                // Extract P/E
-                if let Some(notional) = snapshot.pe_ratio {
-                    candidates.add_candidate(
-                        ticker.clone(),
-                        ScoringFactor::PeRatio,
-                        notional.into(),
-                    );
-                }
+    pub fn new(ranking_config: &RankingConfig, history_config: &HistoryConfig) -> Self {
+        Self {
+            moving_averages: moving_averages(ranking_config),
+            bar_store: BarStore::new(history_config.store_dir()),
+            bar: history_config.bar.clone(),
+            read_history: ranking_config.needs_history(),
+        }
+    }
 
-                // Extract dividend yield
-                if let Some(notional) = snapshot.dividend_yield {
-                    candidates.add_candidate(
-                        ticker.clone(),
-                        ScoringFactor::DividendYield,
-                        notional.into(),
-                    );
-                }
+    /// Reads the bars from the store, which the downloader has brought up to date.
+    ///
+    /// Contracts whose bars fail to load get no technical indicators, with a warning.
+    pub async fn extract_scoring_factors(&self, stock_data: &StockData) -> StockCandidates {
+        let mut candidates = StockCandidates::default();
+        let holdings = stock_data
+            .portfolio
+            .iter()
+            .map(|position| (position.conid, &position.ticker));
+        let universe = stock_data
+            .universe
+            .iter()
+            .map(|entry| (entry.conid, &entry.ticker));
+        for (conid, ticker) in holdings.chain(universe) {
+            let ticker: Ticker = ticker.as_str().into();
 
-                // Extract EMA 20 change
-                if let Some(notional) = snapshot.pema_20 {
-                    candidates.add_candidate(
-                        ticker.clone(),
-                        ScoringFactor::PriceEma20Change,
-                        notional.into(),
-                    );
+            if let Some(snapshot) = stock_data.market_snapshot.get(&conid.into()) {
+                for field in SNAPSHOT_FIELDS {
+                    if let Some(value) = field.factor_value(&snapshot.fields) {
+                        candidates.add_candidate(ticker.clone(), field.factor, value.into());
+                    }
                 }
+            }
 
-                // Extract EMA 200 change
-                if let Some(notional) = snapshot.pema_200 {
-                    candidates.add_candidate(
-                        ticker.clone(),
-                        ScoringFactor::PriceEma200Change,
-                        notional.into(),
-                    );
+            if !self.read_history {
+                continue;
+            }
+            match self.bar_store.load(conid, &self.bar).await {
+                Ok(bars) => {
+                    for (factor, notional) in extract_indicators(&bars, &self.moving_averages) {
+                        candidates.add_candidate(ticker.clone(), factor, notional.into());
+                    }
                 }
+                Err(e) => eprintln!("Warning: Failed to load the history of {}: {:#}", ticker, e),
             }
         }
         candidates
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug, Deserialize)]
+/// Also computes the moving averages of any length weighted in the config.
+fn moving_averages(config: &RankingConfig) -> Vec<ScoringFactor> {
+    let configured = config
+        .weights
+        .keys()
+        .filter(|factor| factor.moving_average_length().is_some())
+        .sorted_unstable_by_key(|factor| factor.to_string());
+    DEFAULT_MOVING_AVERAGES
+        .into_iter()
+        .chain(configured.copied())
+        .unique()
+        .collect()
+}
+
+/// Computes the technical indicators at the latest bar.
+fn extract_indicators(
+    bars: &[Bar],
+    moving_averages: &[ScoringFactor],
+) -> Vec<(ScoringFactor, f64)> {
+    let closes: Vec<_> = bars.iter().map(|bar| bar.close).collect();
+    let highs: Vec<_> = bars.iter().map(|bar| bar.high).collect();
+    let Some(price) = closes.last().copied() else {
+        return Vec::default();
+    };
+    let price_change = |average: f64| price / average - 1.0;
+    let moving_averages = moving_averages.iter().map(|factor| {
+        let average = match *factor {
+            ScoringFactor::PriceSmaChange(length) => {
+                technical_indicator::sma(&closes, length as usize)
+            }
+            ScoringFactor::PriceEmaChange(length) => {
+                technical_indicator::ema(&closes, length as usize)
+            }
+            _ => None,
+        };
+        (*factor, average.map(price_change))
+    });
+    [
+        (ScoringFactor::Rsi14, technical_indicator::rsi(&closes, 14)),
+        (
+            ScoringFactor::MacdHistogram,
+            technical_indicator::macd(&closes, 12, 26, 9).map(|macd| macd.histogram / price),
+        ),
+        (
+            ScoringFactor::BollingerPercentB,
+            technical_indicator::bollinger_percent_b(&closes, 20, 2.0),
+        ),
+        (
+            ScoringFactor::DrawdownFrom52WeekHigh,
+            technical_indicator::drawdown_from_high(&highs, price, TRADING_DAYS_PER_YEAR),
+        ),
+        (
+            ScoringFactor::RealizedVolatility,
+            technical_indicator::realized_volatility(&closes, 20),
+        ),
+    ]
+    .into_iter()
+    .chain(moving_averages)
+    .filter_map(|(factor, value)| Some((factor, value.filter(|v| v.is_finite())?)))
+    .collect()
+}
+
+/// Factor to rank stocks by, named in kebab case in the config, e.g. `dividend-yield`.
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum ScoringFactor {
     /// Price over earnings.
     PeRatio,
 
     DividendYield,
 
-    /// Price change over Exponential Moving Average in 20 days
-    PriceEma20Change,
+    /// Price change over Exponential Moving Average in 20 days as reported by IBKR
+    SnapshotPriceEma20Change,
+
+    /// Price change over Exponential Moving Average in 200 days as reported by IBKR
+    SnapshotPriceEma200Change,
+
+    /// Price change over Simple Moving Average in this many days, computed from the history,
+    /// e.g. `price-sma50-change`.
+    PriceSmaChange(u32),
+
+    /// Price change over Exponential Moving Average in this many days, computed from the history,
+    /// e.g. `price-ema50-change`.
+    ///
+    /// The fields of IBKR are named `snapshot-price-emaN-change` instead.
+    PriceEmaChange(u32),
 
-    /// Price change over Exponential Moving Average in 200 days
-    PriceEma200Change,
+    /// Relative Strength Index in 14 days, from 0 to 100.
+    Rsi14,
+
+    /// MACD (12, 26, 9) histogram relative to the price.
+    MacdHistogram,
+
+    /// Position within the Bollinger Bands (20 days, 2 standard deviations).
+    BollingerPercentB,
+
+    /// Price change from the highest price in 52 weeks.
+    DrawdownFrom52WeekHigh,
+
+    /// Annualized volatility of the daily returns in 20 days.
+    RealizedVolatility,
+
+    /// Price change over Exponential Moving Average in 50 days as reported by IBKR,
+    /// unlike [Self::PriceEmaChange] computed from the history.
+    SnapshotPriceEma50Change,
+
+    /// Price change over Exponential Moving Average in 100 days as reported by IBKR
+    SnapshotPriceEma100Change,
+
+    MarketCap,
+
+    Beta,
+
+    /// Earnings per share.
+    Eps,
+
+    /// Expected dividend per share over the next 12 months.
+    DividendAmount,
+
+    /// Price change from the highest price in 52 weeks as reported by IBKR,
+    /// unlike [Self::DrawdownFrom52WeekHigh] computed from the history.
+    High52Week,
+
+    /// Price change from the lowest price in 52 weeks.
+    Low52Week,
+
+    /// Price change since the previous close, not ranked.
+    ChangePercent,
+
+    /// Not ranked.
+    LastPrice,
+
+    /// Traded shares of the day.
+    Volume,
+
+    /// Implied volatility of the options in 30 days.
+    ImpliedVolatility,
+
+    /// Historical volatility in 30 days as reported by IBKR.
+    HistoricalVolatility,
+}
+
+/// Names of the factors without a length.
+const FACTOR_NAMES: &[(ScoringFactor, &str)] = &[
+    (ScoringFactor::PeRatio, "pe-ratio"),
+    (ScoringFactor::DividendYield, "dividend-yield"),
+    (
+        ScoringFactor::SnapshotPriceEma20Change,
+        "snapshot-price-ema20-change",
+    ),
+    (
+        ScoringFactor::SnapshotPriceEma200Change,
+        "snapshot-price-ema200-change",
+    ),
+    (ScoringFactor::Rsi14, "rsi14"),
+    (ScoringFactor::MacdHistogram, "macd-histogram"),
+    (ScoringFactor::BollingerPercentB, "bollinger-percent-b"),
+    (
+        ScoringFactor::DrawdownFrom52WeekHigh,
+        "drawdown-from-52-week-high",
+    ),
+    (ScoringFactor::RealizedVolatility, "realized-volatility"),
+    (
+        ScoringFactor::SnapshotPriceEma50Change,
+        "snapshot-price-ema50-change",
+    ),
+    (
+        ScoringFactor::SnapshotPriceEma100Change,
+        "snapshot-price-ema100-change",
+    ),
+    (ScoringFactor::MarketCap, "market-cap"),
+    (ScoringFactor::Beta, "beta"),
+    (ScoringFactor::Eps, "eps"),
+    (ScoringFactor::DividendAmount, "dividend-amount"),
+    (ScoringFactor::High52Week, "52-week-high"),
+    (ScoringFactor::Low52Week, "52-week-low"),
+    (ScoringFactor::ChangePercent, "change-percent"),
+    (ScoringFactor::LastPrice, "last-price"),
+    (ScoringFactor::Volume, "volume"),
+    (ScoringFactor::ImpliedVolatility, "implied-volatility"),
+    (ScoringFactor::HistoricalVolatility, "historical-volatility"),
+];
+
+impl ScoringFactor {
+    /// Whether the factor is computed from the history rather than reported in the snapshot.
+    pub fn is_computed_from_history(self) -> bool {
+        matches!(
+            self,
+            Self::PriceSmaChange(_)
+                | Self::PriceEmaChange(_)
+                | Self::Rsi14
+                | Self::MacdHistogram
+                | Self::BollingerPercentB
+                | Self::DrawdownFrom52WeekHigh
+                | Self::RealizedVolatility
+        )
+    }
+
+    /// Length of a moving average computed from the history.
+    pub fn moving_average_length(self) -> Option<u32> {
+        match self {
+            Self::PriceSmaChange(length) | Self::PriceEmaChange(length) => Some(length),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ScoringFactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PriceSmaChange(length) => write!(f, "price-sma{}-change", length),
+            Self::PriceEmaChange(length) => write!(f, "price-ema{}-change", length),
+            factor => {
+                let (_, name) = FACTOR_NAMES
+                    .iter()
+                    .find(|(named, _)| named == factor)
+                    .expect("Every factor without a length has a name");
+                f.write_str(name)
+            }
+        }
+    }
+}
+
+/// Parses the name used in the config, e.g. `dividend-yield`.
+impl FromStr for ScoringFactor {
+    type Err = UnknownScoringFactor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((factor, _)) = FACTOR_NAMES.iter().find(|(_, name)| *name == s) {
+            return Ok(*factor);
+        }
+        let length = |prefix: &str| {
+            s.strip_prefix(prefix)?
+                .strip_suffix("-change")
+                .filter(|length| length.bytes().all(|byte| byte.is_ascii_digit()))?
+                .parse()
+                .ok()
+                .filter(|length| *length > 0)
+        };
+        if let Some(length) = length("price-sma") {
+            Ok(Self::PriceSmaChange(length))
+        } else if let Some(length) = length("price-ema") {
+            Ok(Self::PriceEmaChange(length))
+        } else {
+            Err(UnknownScoringFactor { name: s.into() })
+        }
+    }
+}
+
+impl From<ScoringFactor> for String {
+    fn from(value: ScoringFactor) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for ScoringFactor {
+    type Error = UnknownScoringFactor;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "Unknown scoring factor {}", name)]
+pub struct UnknownScoringFactor {
+    #[error(not(source))]
+    name: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bar_store::MockBarStore;
+    use crate::ibkr_client::PortfolioPosition;
+    use chrono::DateTime;
+    use mockall::predicate::*;
+    use std::collections::HashMap;
+
+    fn build_bars(days: i64) -> Vec<Bar> {
+        (1..=days)
+            .map(|day| Bar {
+                time: DateTime::from_timestamp(day * 86400, 0).unwrap(),
+                open: day as f64,
+                high: day as f64 + 10.0,
+                low: day as f64,
+                close: day as f64,
+                volume: 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extract_indicators_from_short_history() {
+        // Given
+        let bars = build_bars(30);
+
+        // When
+        let factors: Vec<_> = extract_indicators(&bars, &DEFAULT_MOVING_AVERAGES)
+            .into_iter()
+            .map(|(factor, _)| factor)
+            .collect();
+
+        // Then
+        assert_eq!(
+            vec![
+                ScoringFactor::Rsi14,
+                ScoringFactor::BollingerPercentB,
+                ScoringFactor::DrawdownFrom52WeekHigh,
+                ScoringFactor::RealizedVolatility
+            ],
+            factors
+        );
+    }
+
+    #[test]
+    fn extract_configured_moving_averages() {
+        // Given
+        let config = RankingConfig {
+            weights: [
+                (ScoringFactor::PriceSmaChange(20), 1.0),
+                (ScoringFactor::PriceSmaChange(50), 1.0),
+                (ScoringFactor::Rsi14, 1.0),
+            ]
+            .into(),
+        };
+
+        // When
+        let moving_averages = moving_averages(&config);
+
+        // Then
+        assert_eq!(
+            vec![
+                ScoringFactor::PriceSmaChange(50),
+                ScoringFactor::PriceSmaChange(200),
+                ScoringFactor::PriceEmaChange(50),
+                ScoringFactor::PriceSmaChange(20),
+            ],
+            moving_averages
+        );
+    }
+
+    #[tokio::test]
+    async fn extract_indicators_from_bar_store() {
+        // Given
+        let mut bar_store = MockBarStore::default();
+        bar_store
+            .expect_load()
+            .with(eq(1), eq("1d"))
+            .return_once(|_, _| Ok(build_bars(30)));
+        bar_store
+            .expect_load()
+            .with(eq(2), eq("1d"))
+            .return_once(|_, _| Err(anyhow::anyhow!("Corrupted")));
+        let extractor = ScoringFactorExtractor {
+            bar_store,
+            bar: "1d".into(),
+            read_history: true,
+            ..Default::default()
+        };
+        let stock_data = StockData {
+            portfolio: vec![build_position(1, "AAPL"), build_position(2, "MSFT")],
+            ..Default::default()
+        };
+
+        // When
+        let candidates = extractor.extract_scoring_factors(&stock_data).await;
+
+        // Then
+        let candidates: HashMap<_, _> = candidates.iter().collect();
+        assert!(candidates[&Ticker::from("AAPL")].contains_key(&ScoringFactor::Rsi14));
+        assert!(!candidates.contains_key(&Ticker::from("MSFT")));
+    }
+
+    fn build_position(conid: i64, ticker: &str) -> PortfolioPosition {
+        PortfolioPosition {
+            conid,
+            ticker: ticker.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test_case::case("dividend-yield"              => Some(ScoringFactor::DividendYield)            ; "Fixed")]
+    #[test_case::case("52-week-high"                => Some(ScoringFactor::High52Week)               ; "Starting with a digit")]
+    #[test_case::case("price-sma20-change"          => Some(ScoringFactor::PriceSmaChange(20))       ; "SMA")]
+    #[test_case::case("price-ema30-change"          => Some(ScoringFactor::PriceEmaChange(30))       ; "EMA")]
+    #[test_case::case("price-ema20-change"          => Some(ScoringFactor::PriceEmaChange(20))       ; "EMA of a length IBKR reports too")]
+    #[test_case::case("snapshot-price-ema20-change" => Some(ScoringFactor::SnapshotPriceEma20Change) ; "EMA of IBKR")]
+    #[test_case::case("price-sma0-change"           => None                                          ; "Zero length")]
+    #[test_case::case("price-sma+5-change"          => None                                          ; "Signed length")]
+    #[test_case::case("price-sma-change"            => None                                          ; "Missing length")]
+    #[test_case::case("unknown"                     => None                                          ; "Unknown")]
+    fn parse_factor(name: &str) -> Option<ScoringFactor> {
+        name.parse().ok()
+    }
+
+    #[test]
+    fn name_round_trip() {
+        let factors = FACTOR_NAMES
+            .iter()
+            .map(|(factor, _)| *factor)
+            .chain(DEFAULT_MOVING_AVERAGES)
+            .chain([
+                ScoringFactor::PriceSmaChange(7),
+                ScoringFactor::PriceEmaChange(20),
+            ]);
+        for factor in factors {
+            let json = serde_json::to_string(&factor).unwrap();
+            assert_eq!(factor, serde_json::from_str(&json).unwrap());
+        }
+    }
 }
@@ -0,0 +1,468 @@
+//! Registry of the market snapshot fields scored as factors.
+//!
+//! Requesting, parsing, extracting, ranking and rendering the fields are all driven by
+//! [`SNAPSHOT_FIELDS`], so scoring another field of IBKR takes one entry there and a
+//! [`ScoringFactor`] variant.
+
+use crate::scoring_factor_extractor::ScoringFactor;
+use crate::stock_ranker::Ranking;
+use derive_more::Display;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Requested in every snapshot but not scored.
+pub const FIELD_ID_SYMBOL: i32 = 55;
+
+/// Requested in every snapshot to tell whether the data is delayed.
+pub const FIELD_ID_MARKET_DATA_AVAILABILITY: i32 = 6509;
+
+#[rustfmt::skip]
+pub const SNAPSHOT_FIELDS: &[SnapshotField] = &[
+    // P/E ratio of some companies (especially PAH3, merely 3!) feel artificial.
+    SnapshotField::new(7290, "P/E", FieldParser::Number, ScoringFactor::PeRatio, DisplayFormat::Float)
+        .ranked(Ranking::PositiveLeast, 0.0)
+        .always_requested(),
+    // Half of my stocks don't pay dividend, and even they do, it's not a significant
+    // income. Let's not make it too pronounced in to decision making.
+    SnapshotField::new(7287, "Dividend yield", FieldParser::Percentage, ScoringFactor::DividendYield, DisplayFormat::Percentage)
+        .ranked(Ranking::PositiveGreatest, 1.0),
+    SnapshotField::new(7681, "Price/EMA(20)", FieldParser::Percentage, ScoringFactor::SnapshotPriceEma20Change, DisplayFormat::Percentage)
+        .ranked(Ranking::NegativeLeast, 4.0),
+    SnapshotField::new(7678, "Price/EMA(200)", FieldParser::Percentage, ScoringFactor::SnapshotPriceEma200Change, DisplayFormat::Percentage)
+        .ranked(Ranking::PositiveGreatest, 5.0),
+    // Other fields are only requested once weighted in the config
+    SnapshotField::new(7680, "Price/EMA(50)", FieldParser::Percentage, ScoringFactor::SnapshotPriceEma50Change, DisplayFormat::Percentage)
+        .ranked(Ranking::NegativeLeast, 0.0),
+    SnapshotField::new(7679, "Price/EMA(100)", FieldParser::Percentage, ScoringFactor::SnapshotPriceEma100Change, DisplayFormat::Percentage)
+        .ranked(Ranking::PositiveGreatest, 0.0),
+    // Larger companies are safer
+    SnapshotField::new(7289, "Market cap", FieldParser::Number, ScoringFactor::MarketCap, DisplayFormat::SuffixedNumber)
+        .ranked(Ranking::PositiveGreatest, 0.0),
+    // Less volatile than the market wins
+    SnapshotField::new(7718, "Beta", FieldParser::Number, ScoringFactor::Beta, DisplayFormat::Float)
+        .ranked(Ranking::PositiveLeast, 0.0),
+    SnapshotField::new(7291, "EPS", FieldParser::Number, ScoringFactor::Eps, DisplayFormat::Float)
+        .ranked(Ranking::PositiveGreatest, 0.0),
+    SnapshotField::new(7286, "Dividend", FieldParser::Number, ScoringFactor::DividendAmount, DisplayFormat::Float)
+        .ranked(Ranking::PositiveGreatest, 0.0),
+    // Far below the 52-week high wins, like the drawdown from the history
+    SnapshotField::new(7293, "Price/52-week high", FieldParser::Number, ScoringFactor::High52Week, DisplayFormat::Percentage)
+        .relative_to_last_price()
+        .ranked(Ranking::NegativeLeast, 0.0),
+    // Close to the 52-week low wins
+    SnapshotField::new(7294, "Price/52-week low", FieldParser::Number, ScoringFactor::Low52Week, DisplayFormat::Percentage)
+        .relative_to_last_price()
+        .ranked(Ranking::PositiveLeast, 0.0),
+    // Only shown, as neither a price nor the change of a day compares across stocks
+    SnapshotField::new(83, "Change", FieldParser::Percentage, ScoringFactor::ChangePercent, DisplayFormat::Percentage),
+    SnapshotField::new(31, "Last price", FieldParser::Number, ScoringFactor::LastPrice, DisplayFormat::Float),
+    // Liquid stocks win
+    SnapshotField::new(7762, "Volume", FieldParser::Number, ScoringFactor::Volume, DisplayFormat::SuffixedNumber)
+        .ranked(Ranking::PositiveGreatest, 0.0),
+    SnapshotField::new(7283, "Implied volatility", FieldParser::Percentage, ScoringFactor::ImpliedVolatility, DisplayFormat::Percentage)
+        .ranked(Ranking::PositiveLeast, 0.0),
+    SnapshotField::new(7087, "Historical volatility", FieldParser::Percentage, ScoringFactor::HistoricalVolatility, DisplayFormat::Percentage)
+        .ranked(Ranking::PositiveLeast, 0.0),
+];
+
+/// Field of `iserver/marketdata/snapshot` scored as a factor.
+#[derive(Debug)]
+pub struct SnapshotField {
+    pub id: i32,
+
+    /// Human-readable name, also the column of the report.
+    pub name: &'static str,
+
+    pub parser: FieldParser,
+    pub factor: ScoringFactor,
+    pub format: DisplayFormat,
+
+    /// How the factor is ranked, or `None` if it is only shown in the report.
+    pub ranking: Option<Ranking>,
+
+    /// Default weight of the factor, overridden by the config.
+    pub weight: f64,
+
+    /// Whether the factor is the change of the last price from the value rather than the value,
+    /// e.g. how far the price is below the 52-week high.
+    pub relative_to_last_price: bool,
+
+    /// Whether to request the field even if its factor has no weight, to show it in the report.
+    pub always_requested: bool,
+}
+
+impl SnapshotField {
+    const fn new(
+        id: i32,
+        name: &'static str,
+        parser: FieldParser,
+        factor: ScoringFactor,
+        format: DisplayFormat,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            parser,
+            factor,
+            format,
+            ranking: None,
+            weight: 0.0,
+            relative_to_last_price: false,
+            always_requested: false,
+        }
+    }
+
+    const fn always_requested(self) -> Self {
+        Self {
+            always_requested: true,
+            ..self
+        }
+    }
+
+    const fn relative_to_last_price(self) -> Self {
+        Self {
+            relative_to_last_price: true,
+            ..self
+        }
+    }
+
+    const fn ranked(self, ranking: Ranking, weight: f64) -> Self {
+        Self {
+            ranking: Some(ranking),
+            weight,
+            ..self
+        }
+    }
+}
+
+impl SnapshotField {
+    /// Value of the factor among the parsed `fields` of a snapshot.
+    pub fn factor_value(&self, fields: &HashMap<ScoringFactor, f64>) -> Option<f64> {
+        let value = *fields.get(&self.factor)?;
+        if !self.relative_to_last_price {
+            return Some(value);
+        }
+        let last_price = fields.get(&ScoringFactor::LastPrice)?;
+        Some(last_price / value - 1.0).filter(|change| change.is_finite())
+    }
+}
+
+/// Unit of a field without a `%` sign.
+#[derive(Clone, Copy, Debug)]
+pub enum FieldParser {
+    /// e.g. `12.3`
+    Number,
+
+    /// e.g. `3.5`, meaning `0.035`
+    Percentage,
+}
+
+impl FieldParser {
+    /// Parses any format of the gateway, returning `None` if malformed.
+    ///
+    /// Besides plain numbers, there are `%` signs (e.g. `3.5%`), suffixes (e.g. `1.23K` or `4.5M`),
+    /// thousands separators (e.g. `1,234`), placeholders of missing data (e.g. `-` or `N/A`)
+    /// and prefixes qualifying a price (e.g. `C123.4` for the previous close).
+    pub fn parse(self, value: &Value) -> Option<FieldValue> {
+        let scale = match self {
+            Self::Number => 1.0,
+            Self::Percentage => 0.01,
+        };
+        if let Some(number) = value.as_f64() {
+            return Some(FieldValue::Number {
+                value: number * scale,
+                qualifier: None,
+            });
+        }
+
+        let text = value.as_str()?.trim();
+        if matches!(text, "" | "-" | "N/A" | "n/a") {
+            return Some(FieldValue::Unavailable);
+        }
+        let (qualifier, text) = match text.strip_prefix('C') {
+            Some(rest) => (Some(Qualifier::Close), rest),
+            None => match text.strip_prefix('H') {
+                Some(rest) => (Some(Qualifier::Halted), rest),
+                None => (None, text),
+            },
+        };
+        let (text, scale) = match text.strip_suffix('%') {
+            Some(rest) => (rest, 0.01),
+            None => (text, scale),
+        };
+        let (text, multiplier) = match text.chars().last()? {
+            'K' => (&text[..text.len() - 1], 1e3),
+            'M' => (&text[..text.len() - 1], 1e6),
+            'B' => (&text[..text.len() - 1], 1e9),
+            'T' => (&text[..text.len() - 1], 1e12),
+            _ => (text, 1.0),
+        };
+        let number: f64 = text.replace(',', "").parse().ok()?;
+        Some(FieldValue::Number {
+            value: number * multiplier * scale,
+            qualifier,
+        })
+    }
+}
+
+/// Value of a snapshot field.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FieldValue {
+    /// IBKR has no data, e.g. the P/E of a company without earnings.
+    Unavailable,
+
+    Number {
+        value: f64,
+        qualifier: Option<Qualifier>,
+    },
+}
+
+/// What a prefix tells about a price.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Display)]
+#[serde(rename_all = "kebab-case")]
+pub enum Qualifier {
+    /// `C`: Price at the previous close, as there is no trade yet today.
+    #[display(fmt = "close")]
+    Close,
+
+    /// `H`: Trading is halted.
+    #[display(fmt = "halted")]
+    Halted,
+}
+
+/// How fresh the market data of a snapshot is, according to field 6509.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Display)]
+#[serde(rename_all = "kebab-case")]
+pub enum MarketDataAvailability {
+    #[display(fmt = "Real-time")]
+    RealTime,
+
+    #[display(fmt = "Delayed")]
+    Delayed,
+
+    /// Last data before the market closed.
+    #[display(fmt = "Frozen")]
+    Frozen,
+
+    #[display(fmt = "Frozen delayed")]
+    FrozenDelayed,
+
+    /// No market data subscription.
+    #[display(fmt = "Not subscribed")]
+    NotSubscribed,
+}
+
+impl MarketDataAvailability {
+    /// Parses codes like `RpB` or `DPB` by their first letter.
+    pub fn parse(value: &Value) -> Option<Self> {
+        match value.as_str()?.chars().next()? {
+            'R' => Some(Self::RealTime),
+            'D' => Some(Self::Delayed),
+            'Z' => Some(Self::Frozen),
+            'Y' => Some(Self::FrozenDelayed),
+            'N' => Some(Self::NotSubscribed),
+            _ => None,
+        }
+    }
+}
+
+/// How the value of a field is shown in the report.
+#[derive(Clone, Copy, Debug)]
+pub enum DisplayFormat {
+    Float,
+    Percentage,
+
+    /// e.g. `2.71T` for sizes like the market cap
+    SuffixedNumber,
+}
+
+/// Fields the ranking needs under the `weights` configured, which override the defaults.
+///
+/// Requesting a field IBKR has not prepared yet slows down the snapshot,
+/// so the fields without weight are left out.
+pub fn requested_fields(weights: &HashMap<ScoringFactor, f64>) -> Vec<&'static SnapshotField> {
+    let weighted = |field: &SnapshotField| {
+        field.ranking.is_some()
+            && weights.get(&field.factor).copied().unwrap_or(field.weight) != 0.0
+    };
+    let needs_last_price = SNAPSHOT_FIELDS
+        .iter()
+        .any(|field| field.relative_to_last_price && weighted(field));
+    SNAPSHOT_FIELDS
+        .iter()
+        .filter(|field| {
+            weighted(field)
+                || field.always_requested
+                || (needs_last_price && field.factor == ScoringFactor::LastPrice)
+        })
+        .collect()
+}
+
+/// IDs of the `fields` to request in a snapshot, besides those always requested.
+pub fn field_ids(fields: &[&SnapshotField]) -> Vec<i32> {
+    [FIELD_ID_SYMBOL, FIELD_ID_MARKET_DATA_AVAILABILITY]
+        .into_iter()
+        .chain(fields.iter().map(|field| field.id))
+        .collect()
+}
+
+/// Human-readable name of a snapshot field.
+pub fn field_name(field_id: i32) -> String {
+    match field_id {
+        FIELD_ID_SYMBOL => return "Symbol".into(),
+        FIELD_ID_MARKET_DATA_AVAILABILITY => return "Market data availability".into(),
+        _ => {}
+    }
+    SNAPSHOT_FIELDS
+        .iter()
+        .find(|field| field.id == field_id)
+        .map_or_else(|| field_id.to_string(), |field| field.name.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn build_number(value: f64) -> Option<FieldValue> {
+        Some(FieldValue::Number {
+            value,
+            qualifier: None,
+        })
+    }
+
+    fn build_qualified(value: f64, qualifier: Qualifier) -> Option<FieldValue> {
+        Some(FieldValue::Number {
+            value,
+            qualifier: Some(qualifier),
+        })
+    }
+
+    #[test_case::case(FieldParser::Number, "12.5"    => build_number(12.5)                       ; "Number")]
+    #[test_case::case(FieldParser::Number, "-4%"     => build_number(-0.04)                      ; "Percentage sign")]
+    #[test_case::case(FieldParser::Percentage, "3"   => build_number(0.03)                       ; "Percentage without sign")]
+    #[test_case::case(FieldParser::Number, "1.5K"    => build_number(1500.0)                     ; "Thousands")]
+    #[test_case::case(FieldParser::Number, "4.5M"    => build_number(4.5e6)                      ; "Millions")]
+    #[test_case::case(FieldParser::Number, "2B"      => build_number(2e9)                        ; "Billions")]
+    #[test_case::case(FieldParser::Number, "1,234.5" => build_number(1234.5)                     ; "Thousands separator")]
+    #[test_case::case(FieldParser::Number, "C123.5"  => build_qualified(123.5, Qualifier::Close) ; "Close")]
+    #[test_case::case(FieldParser::Number, "H8"      => build_qualified(8.0, Qualifier::Halted)  ; "Halted")]
+    #[test_case::case(FieldParser::Number, "-"       => Some(FieldValue::Unavailable)            ; "Dash")]
+    #[test_case::case(FieldParser::Percentage, "N/A" => Some(FieldValue::Unavailable)            ; "Not applicable")]
+    #[test_case::case(FieldParser::Number, "abc"     => None                                     ; "Malformed")]
+    #[test_case::case(FieldParser::Number, "C"       => None                                     ; "Prefix only")]
+    fn parse(parser: FieldParser, text: &str) -> Option<FieldValue> {
+        parser.parse(&text.into())
+    }
+
+    #[test]
+    fn parse_json_number() {
+        assert_eq!(
+            build_number(-0.5),
+            FieldParser::Percentage.parse(&(-50).into())
+        );
+        assert_eq!(build_number(1.2), FieldParser::Number.parse(&1.2.into()));
+        assert_eq!(None, FieldParser::Number.parse(&true.into()));
+    }
+
+    #[test_case::case("RpB" => Some(MarketDataAvailability::RealTime) ; "Real-time")]
+    #[test_case::case("DPB" => Some(MarketDataAvailability::Delayed)  ; "Delayed")]
+    #[test_case::case("?"   => None                                   ; "Unknown")]
+    fn parse_market_data_availability(code: &str) -> Option<MarketDataAvailability> {
+        MarketDataAvailability::parse(&code.into())
+    }
+
+    #[test]
+    fn registry_without_duplicates() {
+        let ids: HashSet<_> = SNAPSHOT_FIELDS
+            .iter()
+            .map(|field| field.id)
+            .chain([FIELD_ID_SYMBOL, FIELD_ID_MARKET_DATA_AVAILABILITY])
+            .collect();
+        let factors: HashSet<_> = SNAPSHOT_FIELDS.iter().map(|field| field.factor).collect();
+        assert_eq!(SNAPSHOT_FIELDS.len() + 2, ids.len());
+        assert_eq!(SNAPSHOT_FIELDS.len(), factors.len());
+    }
+
+    #[test]
+    fn request_fields_by_default() {
+        let requested: Vec<_> = requested_fields(&HashMap::default())
+            .iter()
+            .map(|field| field.factor)
+            .collect();
+        assert_eq!(
+            vec![
+                ScoringFactor::PeRatio,
+                ScoringFactor::DividendYield,
+                ScoringFactor::SnapshotPriceEma20Change,
+                ScoringFactor::SnapshotPriceEma200Change,
+            ],
+            requested
+        );
+    }
+
+    #[test]
+    fn request_fields_weighted_in_config() {
+        let weights = [
+            (ScoringFactor::DividendYield, 0.0),
+            (ScoringFactor::High52Week, 1.0),
+        ]
+        .into();
+        let requested: Vec<_> = requested_fields(&weights)
+            .iter()
+            .map(|field| field.factor)
+            .collect();
+        assert_eq!(
+            vec![
+                ScoringFactor::PeRatio,
+                ScoringFactor::SnapshotPriceEma20Change,
+                ScoringFactor::SnapshotPriceEma200Change,
+                ScoringFactor::High52Week,
+                ScoringFactor::LastPrice,
+            ],
+            requested
+        );
+    }
+
+    #[test]
+    fn factor_value_relative_to_last_price() {
+        let high = SNAPSHOT_FIELDS
+            .iter()
+            .find(|field| field.factor == ScoringFactor::High52Week)
+            .unwrap();
+        let fields = [
+            (ScoringFactor::High52Week, 200.0),
+            (ScoringFactor::LastPrice, 150.0),
+        ]
+        .into();
+        assert_eq!(Some(-0.25), high.factor_value(&fields));
+        assert_eq!(
+            None,
+            high.factor_value(&[(ScoringFactor::High52Week, 200.0)].into())
+        );
+        assert_eq!(
+            None,
+            high.factor_value(
+                &[
+                    (ScoringFactor::High52Week, 0.0),
+                    (ScoringFactor::LastPrice, 1.0)
+                ]
+                .into()
+            )
+        );
+    }
+
+    #[test]
+    fn name_of_field() {
+        assert_eq!("Dividend yield", field_name(7287));
+        assert_eq!("Price/52-week high", field_name(7293));
+        assert_eq!("Symbol", field_name(FIELD_ID_SYMBOL));
+        assert_eq!(
+            "Market data availability",
+            field_name(FIELD_ID_MARKET_DATA_AVAILABILITY)
+        );
+        assert_eq!("1", field_name(1));
+    }
+}
@@ -0,0 +1,495 @@
+use crate::config::PortfolioConfig;
+use crate::contract_details_downloader::ContractDetails;
+use crate::ibkr_client::PortfolioPosition;
+use crate::position_filter::PositionFilter;
+use crate::scoring_factor_extractor::ScoringFactor;
+use crate::stock_data_downloader::MarketSnapshot;
+use crate::stock_data_downloader::StockData;
+use anyhow::Context;
+use chrono::Days;
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[mockall_double::double]
+use crate::clock::Clock;
+
+/// Reads the holdings from statements exported from IBKR instead of the gateway.
+///
+/// Supports Activity Statements in CSV and Flex Queries in XML with the sections Open Positions,
+/// Trades, Dividends (Cash Transactions in Flex) and Financial Instrument Information.
+/// Statements carry no market data, so the snapshot only has the dividend yield, computed from the
+/// dividends paid in the year before the statement date over the current value of the position.
+#[derive(Default)]
+pub struct StatementImporter {
+    position_filter: PositionFilter,
+    clock: Clock,
+}
+
+impl StatementImporter {
+    pub fn new(config: PortfolioConfig) -> Self {
+        Self {
+            position_filter: PositionFilter::new(config),
+            clock: Default::default(),
+        }
+    }
+
+    /// Stock data of each account in a statement file.
+    pub async fn import(&self, path: &Path) -> anyhow::Result<Vec<(String, StockData)>> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let statements = if text.trim_start().starts_with('<') {
+            parse_flex(&text)
+        } else {
+            parse_activity_statement(&text).map(|statement| vec![statement])
+        }
+        .with_context(|| format!("Failed to parse statement {}", path.display()))?;
+        let result = statements
+            .into_iter()
+            .map(|statement| {
+                (
+                    statement.account_id.clone(),
+                    self.build_stock_data(statement),
+                )
+            })
+            .collect();
+        Ok(result)
+    }
+}
+
+impl StatementImporter {
+    fn build_stock_data(&self, statement: Statement) -> StockData {
+        let mut positions = statement.positions;
+        for position in &mut positions {
+            position.realizedPnl = statement
+                .realized_pnl
+                .get(&position.conid)
+                .copied()
+                .unwrap_or_default();
+        }
+        let (portfolio, filtered_positions) = self.position_filter.apply(positions);
+
+        let date = statement.date;
+        let year_before = date.and_then(|date| date.checked_sub_days(Days::new(365)));
+        let mut dividends: HashMap<i64, f64> = HashMap::default();
+        for dividend in &statement.dividends {
+            let in_last_year = match (year_before, date) {
+                (Some(year_before), Some(date)) => {
+                    year_before < dividend.date && dividend.date <= date
+                }
+                _ => true,
+            };
+            if in_last_year {
+                *dividends.entry(dividend.conid).or_default() += dividend.amount;
+            }
+        }
+        let market_snapshot = portfolio
+            .iter()
+            .map(|position| {
+                let dividend_yield = (position.mktValue != 0.0).then(|| {
+                    dividends.get(&position.conid).copied().unwrap_or_default() / position.mktValue
+                });
+                let snapshot = MarketSnapshot {
+                    conid: position.conid,
+                    fields: dividend_yield
+                        .map(|dividend_yield| (ScoringFactor::DividendYield, dividend_yield))
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                };
+                (position.conid.into(), snapshot)
+            })
+            .collect();
+
+        let timestamp = match date.and_then(|date| date.and_hms_opt(0, 0, 0)) {
+            Some(time) => time.and_utc(),
+            None => self.clock.now(),
+        };
+        StockData {
+            market_snapshot,
+            contracts: statement
+                .contracts
+                .into_iter()
+                .map(|(conid, details)| (conid.into(), details))
+                .collect(),
+            portfolio,
+            universe: Vec::default(),
+            incomplete_snapshots: HashMap::default(),
+            filtered_positions,
+            timestamp,
+        }
+    }
+}
+
+/// Holdings of one account as of the end of a statement.
+#[derive(Default)]
+struct Statement {
+    account_id: String,
+    date: Option<NaiveDate>,
+    positions: Vec<PortfolioPosition>,
+    contracts: HashMap<i64, ContractDetails>,
+    dividends: Vec<Dividend>,
+    realized_pnl: HashMap<i64, f64>,
+}
+
+struct Dividend {
+    conid: i64,
+    date: NaiveDate,
+    amount: f64,
+}
+
+/// Parses an Activity Statement where each line starts with its section and whether it is a
+/// header or data, e.g. `Open Positions,Data,Summary,Stocks,USD,AAPL,...`.
+///
+/// Only the Financial Instrument Information section tells the contract IDs, so the other
+/// sections are matched to it by symbol.
+fn parse_activity_statement(text: &str) -> anyhow::Result<Statement> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut headers: HashMap<String, Vec<String>> = HashMap::default();
+    let mut statement = Statement::default();
+    let mut positions = Vec::default();
+    let mut conids: HashMap<String, i64> = HashMap::default();
+    let mut contracts: HashMap<String, ContractDetails> = HashMap::default();
+    let mut realized_pnl: HashMap<String, f64> = HashMap::default();
+    let mut dividends = Vec::default();
+    for record in reader.records() {
+        let record = record?;
+        let (Some(section), Some(kind)) = (record.get(0), record.get(1)) else {
+            continue;
+        };
+        let fields = record.iter().skip(2);
+        if kind == "Header" {
+            headers.insert(section.into(), fields.map(String::from).collect());
+            continue;
+        }
+        if kind != "Data" {
+            continue;
+        }
+        let Some(header) = headers.get(section) else {
+            continue;
+        };
+        let row: HashMap<_, _> = header.iter().map(String::as_str).zip(fields).collect();
+        let get = |name: &str| row.get(name).copied().unwrap_or_default();
+        match section {
+            "Account Information" if get("Field Name") == "Account" => {
+                statement.account_id = get("Field Value")
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .into();
+            }
+            "Statement" if get("Field Name") == "Period" => {
+                // Either a single day or a range like `January 1, 2024 - December 31, 2024`
+                let end = get("Field Value").rsplit(" - ").next().unwrap_or_default();
+                statement.date = parse_date(end);
+            }
+            "Open Positions" if get("DataDiscriminator") == "Summary" => {
+                let position = PortfolioPosition {
+                    conid: 0,
+                    ticker: get("Symbol").into(),
+                    position: parse_number(get("Quantity"))?,
+                    assetClass: asset_class(get("Asset Category")),
+                    mktPrice: parse_number(get("Close Price"))?,
+                    mktValue: parse_number(get("Value"))?,
+                    avgCost: parse_number(get("Cost Price"))?,
+                    unrealizedPnl: parse_number(get("Unrealized P/L"))?,
+                    realizedPnl: 0.0,
+                    currency: get("Currency").into(),
+                };
+                positions.push(position);
+            }
+            "Financial Instrument Information" => {
+                let symbol = get("Symbol");
+                if let Ok(conid) = get("Conid").parse() {
+                    conids.insert(symbol.into(), conid);
+                }
+                let details = ContractDetails {
+                    long_name: non_empty(get("Description")),
+                    exchange: non_empty(get("Listing Exch")),
+                    ..Default::default()
+                };
+                contracts.insert(symbol.into(), details);
+            }
+            "Trades" if get("DataDiscriminator") == "Order" => {
+                *realized_pnl.entry(get("Symbol").into()).or_default() +=
+                    parse_number(get("Realized P/L"))?;
+            }
+            "Dividends" => {
+                // The description starts with the symbol, e.g. `AAPL(US0378331005) Cash Dividend`
+                let symbol = get("Description").split('(').next().unwrap_or_default();
+                if let Some(date) = parse_date(get("Date")) {
+                    dividends.push((
+                        symbol.trim().to_string(),
+                        date,
+                        parse_number(get("Amount"))?,
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    if statement.account_id.is_empty() {
+        anyhow::bail!("No account found in the Account Information section");
+    }
+
+    for mut position in positions {
+        let Some(conid) = conids.get(&position.ticker).copied() else {
+            eprintln!(
+                "Warning: Skipping {} without a contract ID in the Financial Instrument Information",
+                position.ticker
+            );
+            continue;
+        };
+        position.conid = conid;
+        if let Some(mut details) = contracts.remove(&position.ticker) {
+            details.currency = non_empty(&position.currency);
+            statement.contracts.insert(conid, details);
+        }
+        if let Some(pnl) = realized_pnl.get(&position.ticker) {
+            statement.realized_pnl.insert(conid, *pnl);
+        }
+        statement.positions.push(position);
+    }
+    statement.dividends = dividends
+        .into_iter()
+        .filter_map(|(symbol, date, amount)| {
+            let conid = *conids.get(&symbol)?;
+            Some(Dividend {
+                conid,
+                date,
+                amount,
+            })
+        })
+        .collect();
+    Ok(statement)
+}
+
+/// Parses a Flex Query, which has a `FlexStatement` element for each account.
+fn parse_flex(text: &str) -> anyhow::Result<Vec<Statement>> {
+    let document = roxmltree::Document::parse(text)?;
+    let mut statements = Vec::default();
+    for node in document
+        .descendants()
+        .filter(|node| node.has_tag_name("FlexStatement"))
+    {
+        let mut statement = Statement {
+            account_id: node
+                .attribute("accountId")
+                .context("FlexStatement without an account ID")?
+                .into(),
+            date: node.attribute("toDate").and_then(parse_date),
+            ..Default::default()
+        };
+        for child in node.descendants() {
+            let attribute = |name: &str| child.attribute(name).unwrap_or_default();
+            let conid = || -> anyhow::Result<i64> {
+                attribute("conid").parse().with_context(|| {
+                    format!("Malformed contract ID in {}", child.tag_name().name())
+                })
+            };
+            match child.tag_name().name() {
+                "OpenPosition" if matches!(attribute("levelOfDetail"), "" | "SUMMARY") => {
+                    let conid = conid()?;
+                    statement.positions.push(PortfolioPosition {
+                        conid,
+                        ticker: attribute("symbol").into(),
+                        position: parse_number(attribute("position"))?,
+                        assetClass: attribute("assetCategory").into(),
+                        mktPrice: parse_number(attribute("markPrice"))?,
+                        mktValue: parse_number(attribute("positionValue"))?,
+                        avgCost: parse_number(attribute("costBasisPrice"))?,
+                        unrealizedPnl: parse_number(attribute("fifoPnlUnrealized"))?,
+                        realizedPnl: 0.0,
+                        currency: attribute("currency").into(),
+                    });
+                    let details = ContractDetails {
+                        long_name: non_empty(attribute("description")),
+                        exchange: non_empty(attribute("listingExchange")),
+                        currency: non_empty(attribute("currency")),
+                        ..Default::default()
+                    };
+                    statement.contracts.insert(conid, details);
+                }
+                "Trade" => {
+                    *statement.realized_pnl.entry(conid()?).or_default() +=
+                        parse_number(attribute("fifoPnlRealized"))?;
+                }
+                "CashTransaction" if attribute("type") == "Dividends" => {
+                    let date = parse_date(attribute("dateTime"))
+                        .or_else(|| parse_date(attribute("reportDate")));
+                    if let Some(date) = date {
+                        statement.dividends.push(Dividend {
+                            conid: conid()?,
+                            date,
+                            amount: parse_number(attribute("amount"))?,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        statements.push(statement);
+    }
+    if statements.is_empty() {
+        anyhow::bail!("No FlexStatement found");
+    }
+    Ok(statements)
+}
+
+/// Asset class as in the portfolio API, from the asset category of an Activity Statement.
+fn asset_class(category: &str) -> String {
+    match category {
+        "Stocks" => "STK",
+        "Equity and Index Options" => "OPT",
+        "Futures" => "FUT",
+        "Bonds" => "BOND",
+        "Mutual Funds" => "FUND",
+        "Warrants" => "WAR",
+        "Forex" => "CASH",
+        other => other,
+    }
+    .into()
+}
+
+/// Parses an amount that is empty when zero and may have thousands separators.
+fn parse_number(text: &str) -> anyhow::Result<f64> {
+    let text = text.trim().replace(',', "");
+    if text.is_empty() || text == "--" {
+        return Ok(0.0);
+    }
+    text.parse()
+        .with_context(|| format!("Malformed number: {}", text))
+}
+
+/// Parses a date in any format used by the statements, ignoring the time after it.
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    let text = text.trim();
+    let date = text.split([';', ' ']).next().unwrap_or_default();
+    NaiveDate::parse_from_str(date, "%Y%m%d")
+        .or_else(|_| NaiveDate::parse_from_str(date.trim_end_matches(','), "%Y-%m-%d"))
+        .or_else(|_| NaiveDate::parse_from_str(text, "%B %d, %Y"))
+        .ok()
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    (!text.is_empty()).then(|| text.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ACTIVITY_STATEMENT: &str = r#"Statement,Header,Field Name,Field Value
+Statement,Data,Period,"January 1, 2024 - December 31, 2024"
+Account Information,Header,Field Name,Field Value
+Account Information,Data,Account,U1234567
+Open Positions,Header,DataDiscriminator,Asset Category,Currency,Symbol,Quantity,Mult,Cost Price,Cost Basis,Close Price,Value,Unrealized P/L,Code
+Open Positions,Data,Summary,Stocks,USD,AAPL,10,1,150,1500,200,"2,000",500,
+Open Positions,Data,Summary,Stocks,USD,MSFT,5,1,300,1500,400,2000,500,
+Open Positions,Data,Summary,Equity and Index Options,USD,AAPL 240119C00200000,1,100,5,500,1,100,-400,
+Open Positions,Total,,Stocks,USD,,,,,3000,,4000,1000,
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code
+Trades,Data,Order,Stocks,USD,AAPL,"2024-06-03, 10:00:00",-5,190,190,950,-1,-750,199,0,C
+Dividends,Header,Currency,Date,Description,Amount
+Dividends,Data,USD,2024-05-16,AAPL(US0378331005) Cash Dividend USD 0.25 per Share (Ordinary Dividend),2.5
+Dividends,Data,USD,2023-11-16,AAPL(US0378331005) Cash Dividend USD 0.24 per Share (Ordinary Dividend),2.4
+Dividends,Data,Total,,,4.9
+Financial Instrument Information,Header,Asset Category,Symbol,Description,Conid,Security ID,Listing Exch,Multiplier,Type,Code
+Financial Instrument Information,Data,Stocks,AAPL,APPLE INC,265598,US0378331005,NASDAQ,1,COMMON,
+Financial Instrument Information,Data,Stocks,MSFT,MICROSOFT CORP,272093,US5949181045,NASDAQ,1,COMMON,
+Financial Instrument Information,Header,Asset Category,Symbol,Description,Conid,Underlying,Listing Exch,Multiplier,Expiry,Delivery Month,Type,Strike,Code
+Financial Instrument Information,Data,Equity and Index Options,AAPL 240119C00200000,AAPL 19JAN24 200 C,654321,AAPL,CBOE,100,2024-01-19,2024-01,C,200,
+"#;
+
+    const FLEX_QUERY: &str = r#"<FlexQueryResponse queryName="Holdings" type="AF">
+<FlexStatements count="1">
+<FlexStatement accountId="U7654321" fromDate="20240101" toDate="20241231" whenGenerated="20250101;120000">
+<OpenPositions>
+<OpenPosition accountId="U7654321" currency="USD" assetCategory="STK" symbol="AAPL" description="APPLE INC" conid="265598" listingExchange="NASDAQ" position="10" markPrice="200" positionValue="2000" costBasisPrice="150" fifoPnlUnrealized="500" levelOfDetail="SUMMARY" />
+<OpenPosition accountId="U7654321" currency="USD" assetCategory="STK" symbol="AAPL" conid="265598" position="10" levelOfDetail="LOT" />
+</OpenPositions>
+<Trades>
+<Trade conid="265598" symbol="AAPL" fifoPnlRealized="199" />
+<Order conid="265598" symbol="AAPL" fifoPnlRealized="199" />
+</Trades>
+<CashTransactions>
+<CashTransaction type="Dividends" conid="265598" amount="2.5" dateTime="20240516;202000" />
+<CashTransaction type="Withholding Tax" conid="265598" amount="-0.38" dateTime="20240516;202000" />
+</CashTransactions>
+</FlexStatement>
+</FlexStatements>
+</FlexQueryResponse>
+"#;
+
+    fn build_importer() -> StatementImporter {
+        StatementImporter::new(Default::default())
+    }
+
+    fn assert_apple(stock_data: &StockData) {
+        let apple = &stock_data.portfolio[0];
+        assert_eq!(265598, apple.conid);
+        assert_eq!("AAPL", apple.ticker);
+        assert_eq!(10.0, apple.position);
+        assert_eq!(2000.0, apple.mktValue);
+        assert_eq!(150.0, apple.avgCost);
+        assert_eq!(199.0, apple.realizedPnl);
+        let snapshot = &stock_data.market_snapshot[&265598.into()];
+        assert_eq!(
+            HashMap::from([(ScoringFactor::DividendYield, 2.5 / 2000.0)]),
+            snapshot.fields
+        );
+        let contract = &stock_data.contracts[&265598.into()];
+        assert_eq!(Some("NASDAQ".into()), contract.exchange);
+        assert_eq!(Some("USD".into()), contract.currency);
+        assert_eq!(
+            "2024-12-31T00:00:00+00:00",
+            stock_data.timestamp.to_rfc3339()
+        );
+    }
+
+    #[test]
+    fn import_activity_statement() {
+        // When
+        let statement = parse_activity_statement(ACTIVITY_STATEMENT).unwrap();
+        let stock_data = build_importer().build_stock_data(statement);
+
+        // Then
+        assert_eq!(2, stock_data.portfolio.len());
+        assert_apple(&stock_data);
+        assert_eq!(272093, stock_data.portfolio[1].conid);
+        assert_eq!(1, stock_data.filtered_positions.len());
+    }
+
+    #[test]
+    fn import_flex_query() {
+        // When
+        let mut statements = parse_flex(FLEX_QUERY).unwrap();
+
+        // Then
+        assert_eq!(1, statements.len());
+        let statement = statements.pop().unwrap();
+        assert_eq!("U7654321", statement.account_id);
+        let stock_data = build_importer().build_stock_data(statement);
+        assert_eq!(1, stock_data.portfolio.len());
+        assert_apple(&stock_data);
+    }
+
+    #[test]
+    fn import_without_account() {
+        assert!(parse_activity_statement("Statement,Header,Field Name,Field Value\n").is_err());
+    }
+
+    #[test_case::case("20241231"                => Some((2024, 12, 31)) ; "Flex")]
+    #[test_case::case("20240516;202000"         => Some((2024, 5, 16))  ; "Flex with time")]
+    #[test_case::case("2024-06-03, 10:00:00"    => Some((2024, 6, 3))   ; "Activity statement with time")]
+    #[test_case::case("December 31, 2024"       => Some((2024, 12, 31)) ; "Period")]
+    #[test_case::case(""                        => None                 ; "Empty")]
+    fn parse_date(text: &str) -> Option<(i32, u32, u32)> {
+        use chrono::Datelike;
+        super::parse_date(text).map(|date| (date.year(), date.month(), date.day()))
+    }
+}
@@ -1,39 +1,57 @@
+use crate::config::CacheConfig;
+use crate::market_calendar::MarketCalendar;
 use crate::stock_data_downloader::StockData;
 use crate::stock_data_downloader::StockDataDownloader;
+use crate::versioned;
 use anyhow::Context;
 use chrono::DateTime;
 use chrono::Utc;
+use clap::ValueEnum;
+use derive_more::Display;
+use serde::Serialize;
+use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 
+#[mockall_double::double]
+use crate::clock::Clock;
+
+const CACHE_EXTENSION: &str = "json";
+
+#[cfg_attr(test, derive(Default))]
 pub struct StockDataCacher {
-    downloader: StockDataDownloader,
-    cache_path: PathBuf,
+    downloader: Rc<StockDataDownloader>,
+    cache_dir: PathBuf,
+    ttl: Duration,
+    calendar: MarketCalendar,
+    clock: Clock,
 }
 
-impl Default for StockDataCacher {
-    fn default() -> Self {
-        let mut cache_path = std::env::temp_dir();
-        cache_path.push("ibkr-toy-cache.json");
+impl StockDataCacher {
+    pub fn new(
+        downloader: Rc<StockDataDownloader>,
+        config: &CacheConfig,
+        calendar: MarketCalendar,
+    ) -> Self {
         Self {
-            downloader: StockDataDownloader::default(),
-            cache_path,
+            downloader,
+            cache_dir: config.dir(),
+            ttl: Duration::from_millis(config.ttl_millis),
+            calendar,
+            clock: Default::default(),
         }
     }
-}
 
-impl StockDataCacher {
-    pub async fn fetch(&self, account_id: &str, use_cache: bool) -> anyhow::Result<StockData> {
-        if !use_cache {
-            println!("Downloading stock data")
-        } else if let Ok(stock_data) = self.read_cache().await {
-            if !cache_outdated(stock_data.timestamp) {
-                println!("Generating report using cached data");
-                return Ok(stock_data);
-            } else {
-                println!("Cache is outdated");
-            }
-        } else {
-            println!("Stock data not found in cache");
+    pub async fn fetch(
+        &self,
+        account_id: &str,
+        policy: RefreshPolicy,
+    ) -> anyhow::Result<FetchedStockData> {
+        let path = self.cache_path(account_id)?;
+        if let Some(fetched) = self.fetch_from_cache(account_id, policy).await? {
+            return Ok(fetched);
         }
 
         println!("Downloading stock data from IBKR");
@@ -44,21 +62,452 @@ impl StockDataCacher {
             .context("Failed to download stock data")?;
 
         let stock_data_serialized =
-            serde_json::to_string(&stock_data).context("Failed to serialize stock data to JSON")?;
-        tokio::fs::write(&self.cache_path, stock_data_serialized)
+            versioned::to_string(&stock_data).context("Failed to serialize stock data to JSON")?;
+        write_private(&self.cache_dir, &path, stock_data_serialized.as_bytes())
             .await
             .context("Failed to write cache")?;
 
-        Ok(stock_data)
+        Ok(FetchedStockData {
+            stock_data,
+            source: DataSource::Gateway,
+        })
+    }
+
+    /// Lists the cached accounts.
+    pub async fn list(&self) -> anyhow::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::default();
+        for account_id in self.cached_account_ids().await? {
+            let status = match self.read_cache(&account_id).await {
+                Ok(stock_data) if self.cache_outdated(&stock_data) => {
+                    CacheStatus::Outdated(stock_data.timestamp)
+                }
+                Ok(stock_data) => CacheStatus::Fresh(stock_data.timestamp),
+                Err(e) => CacheStatus::Unreadable(format!("{:#}", e)),
+            };
+            entries.push(CacheEntry {
+                account: account_id,
+                timestamp: status.timestamp(),
+                status: status.to_string(),
+            });
+        }
+        entries.sort_unstable_by(|x, y| x.account.cmp(&y.account));
+        Ok(entries)
+    }
+
+    /// Accounts having a cache, sorted.
+    pub async fn cached_account_ids(&self) -> anyhow::Result<Vec<String>> {
+        let mut account_ids = Vec::default();
+        let mut dir = match tokio::fs::read_dir(&self.cache_dir).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(account_ids),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read {}", self.cache_dir.display()))
+            }
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == CACHE_EXTENSION) {
+                if let Some(stem) = path.file_stem() {
+                    account_ids.push(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+        account_ids.sort_unstable();
+        Ok(account_ids)
+    }
+
+    pub async fn show(&self, account_id: &str) -> anyhow::Result<StockData> {
+        self.read_cache(account_id)
+            .await
+            .with_context(|| format!("No readable cache of account {}", account_id))
+    }
+
+    /// Deletes the cache of an account or of all accounts, returning the deleted accounts.
+    pub async fn clear(&self, account_id: Option<&str>) -> anyhow::Result<Vec<String>> {
+        let account_ids = match account_id {
+            Some(account_id) => vec![account_id.to_string()],
+            None => self.cached_account_ids().await?,
+        };
+        let mut cleared = Vec::default();
+        for account_id in account_ids {
+            let path = self.cache_path(&account_id)?;
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => cleared.push(account_id),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to delete {}", path.display()))
+                }
+            }
+        }
+        Ok(cleared)
+    }
+}
+
+impl StockDataCacher {
+    async fn read_cache(&self, account_id: &str) -> anyhow::Result<StockData> {
+        let path = self.cache_path(account_id)?;
+        let cache = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        versioned::from_str(&cache)
+            .with_context(|| format!("Incompatible cache {}", path.display()))
     }
 
-    async fn read_cache(&self) -> anyhow::Result<StockData> {
-        let cache = tokio::fs::read_to_string(&self.cache_path).await?;
-        let stock_data = serde_json::from_str(&cache)?;
-        Ok(stock_data)
+    /// Cached data to use under `policy`, or `None` if it is to be downloaded.
+    async fn fetch_from_cache(
+        &self,
+        account_id: &str,
+        policy: RefreshPolicy,
+    ) -> anyhow::Result<Option<FetchedStockData>> {
+        if policy == RefreshPolicy::Always {
+            return Ok(None);
+        }
+        if !self.cache_path(account_id)?.exists() {
+            if policy == RefreshPolicy::Never {
+                anyhow::bail!(
+                    "No cached stock data of account {} to use offline",
+                    account_id
+                );
+            }
+            println!("Stock data not found in cache");
+            return Ok(None);
+        }
+        let stock_data = match self.read_cache(account_id).await {
+            Ok(stock_data) => stock_data,
+            Err(e) if policy == RefreshPolicy::Never => return Err(e),
+            Err(e) => {
+                eprintln!("Warning: Ignoring the cache: {:#}", e);
+                return Ok(None);
+            }
+        };
+        if !self.cache_outdated(&stock_data) {
+            println!("Generating report using cached data");
+            return Ok(Some(FetchedStockData {
+                stock_data,
+                source: DataSource::Cache,
+            }));
+        }
+        if policy == RefreshPolicy::IfStale {
+            println!("Cache is outdated");
+            return Ok(None);
+        }
+        eprintln!(
+            "Warning: Using outdated cache of account {} from {}",
+            account_id,
+            stock_data.timestamp.to_rfc3339()
+        );
+        Ok(Some(FetchedStockData {
+            stock_data,
+            source: DataSource::OutdatedCache,
+        }))
+    }
+
+    /// Rejects account IDs that are not a plain file name, e.g. `../x`.
+    fn cache_path(&self, account_id: &str) -> anyhow::Result<PathBuf> {
+        let valid = !account_id.is_empty()
+            && account_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            anyhow::bail!("Invalid account ID: {:?}", account_id);
+        }
+        Ok(self
+            .cache_dir
+            .join(format!("{}.{}", account_id, CACHE_EXTENSION)))
+    }
+
+    /// Cached data is current until the next session of its exchanges closes.
+    ///
+    /// Without any known exchange, it is current until the TTL.
+    fn cache_outdated(&self, stock_data: &StockData) -> bool {
+        let now = self.clock.now();
+        let exchanges = stock_data
+            .contracts
+            .values()
+            .filter_map(|contract| contract.exchange.as_deref());
+        if let Some(next_close) = self.calendar.next_close(exchanges, stock_data.timestamp) {
+            return now >= next_close;
+        }
+        let age = now - stock_data.timestamp;
+        age.to_std().is_ok_and(|age| age >= self.ttl)
+    }
+}
+
+/// When to download stock data instead of using the cache.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RefreshPolicy {
+    /// Always downloads.
+    #[default]
+    Always,
+
+    /// Downloads if the cache is missing or outdated.
+    IfStale,
+
+    /// Never downloads, failing if the cache is missing.
+    Never,
+
+    /// Downloads only if the cache is missing, using outdated data with a warning.
+    PreferCache,
+}
+
+pub struct FetchedStockData {
+    pub stock_data: StockData,
+    pub source: DataSource,
+}
+
+/// Where the stock data of a report comes from.
+#[derive(Display, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DataSource {
+    #[display(fmt = "Gateway")]
+    Gateway,
+
+    #[display(fmt = "Cache")]
+    Cache,
+
+    #[display(fmt = "Outdated cache")]
+    OutdatedCache,
+
+    #[display(fmt = "Statement")]
+    Statement,
+}
+
+/// Writes a file only the current user can read, in a directory only they can enter.
+pub async fn write_private(dir: &Path, path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut dir_builder = tokio::fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    dir_builder.mode(0o700);
+    dir_builder.create(dir).await?;
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(content).await?;
+    file.flush().await
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct CacheEntry {
+    account: String,
+    timestamp: String,
+    status: String,
+}
+
+#[derive(Display)]
+enum CacheStatus {
+    #[display(fmt = "Fresh")]
+    Fresh(DateTime<Utc>),
+
+    #[display(fmt = "Outdated")]
+    Outdated(DateTime<Utc>),
+
+    #[display(fmt = "Unreadable: {}", _0)]
+    Unreadable(String),
+}
+
+impl CacheStatus {
+    fn timestamp(&self) -> String {
+        match self {
+            Self::Fresh(timestamp) | Self::Outdated(timestamp) => timestamp.to_rfc3339(),
+            Self::Unreadable(_) => Default::default(),
+        }
     }
 }
 
-fn cache_outdated(timstamp: DateTime<Utc>) -> bool {
-    (Utc::now() - timstamp).num_days() >= 1
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract_details_downloader::ContractDetails;
+
+    fn build_cacher(name: &str) -> StockDataCacher {
+        let mut clock = Clock::default();
+        clock
+            .expect_now()
+            .return_const(DateTime::from_timestamp(3600 * 24, 0).unwrap());
+        StockDataCacher {
+            cache_dir: std::env::temp_dir().join(format!(
+                "ibkr-toy-test-cache-{}-{}",
+                name,
+                std::process::id()
+            )),
+            ttl: Duration::from_secs(3600),
+            clock,
+            ..Default::default()
+        }
+    }
+
+    async fn write_cache(cacher: &StockDataCacher, account_id: &str, timestamp: i64) {
+        let stock_data = StockData {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            ..Default::default()
+        };
+        let content = versioned::to_string(&stock_data).unwrap();
+        write_private(
+            &cacher.cache_dir,
+            &cacher.cache_path(account_id).unwrap(),
+            content.as_bytes(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test_case::case(3600 * 23 + 1 => false ; "Within TTL")]
+    #[test_case::case(3600 * 23     => true  ; "At TTL")]
+    #[test_case::case(3600 * 25     => false ; "In the future")]
+    fn cache_outdated(timestamp: i64) -> bool {
+        let stock_data = StockData {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            ..Default::default()
+        };
+        build_cacher("outdated").cache_outdated(&stock_data)
+    }
+
+    // 2024-03-08 is a Friday, and NASDAQ closes at 21:00 UTC on that day
+    #[test_case::case("2024-03-08T20:00:00Z", "2024-03-08T20:59:00Z" => false ; "Before close")]
+    #[test_case::case("2024-03-08T20:00:00Z", "2024-03-08T21:00:00Z" => true  ; "After close")]
+    #[test_case::case("2024-03-08T22:00:00Z", "2024-03-10T22:00:00Z" => false ; "Over the weekend")]
+    fn cache_outdated_by_calendar(timestamp: &str, now: &str) -> bool {
+        let mut clock = Clock::default();
+        clock.expect_now().return_const(DateTime::<Utc>::from(
+            DateTime::parse_from_rfc3339(now).unwrap(),
+        ));
+        let cacher = StockDataCacher {
+            calendar: MarketCalendar::new(&Default::default()),
+            clock,
+            ..Default::default()
+        };
+        let stock_data = StockData {
+            contracts: [(
+                1.into(),
+                ContractDetails {
+                    exchange: Some("NASDAQ".into()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            timestamp: DateTime::parse_from_rfc3339(timestamp).unwrap().into(),
+            ..Default::default()
+        };
+        cacher.cache_outdated(&stock_data)
+    }
+
+    #[tokio::test]
+    async fn list_and_clear() {
+        // Given
+        let cacher = build_cacher("list");
+        write_cache(&cacher, "U2", 0).await;
+        write_cache(&cacher, "U1", 3600 * 24).await;
+        tokio::fs::write(cacher.cache_dir.join("U3.json"), "{")
+            .await
+            .unwrap();
+
+        // When
+        let statuses: Vec<_> = cacher
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.account, entry.status))
+            .collect();
+        let cleared_one = cacher.clear(Some("U1")).await.unwrap();
+        let cleared_all = cacher.clear(None).await.unwrap();
+
+        // Then
+        assert_eq!(("U1".to_string(), "Fresh".to_string()), statuses[0]);
+        assert_eq!(("U2".to_string(), "Outdated".to_string()), statuses[1]);
+        assert!(statuses[2].1.starts_with("Unreadable"));
+        assert_eq!(vec!["U1".to_string()], cleared_one);
+        assert_eq!(2, cleared_all.len());
+        assert!(cacher.list().await.unwrap().is_empty());
+        tokio::fs::remove_dir(&cacher.cache_dir).await.unwrap();
+    }
+
+    #[test_case::case(RefreshPolicy::Always,      Some(0)         => None                                ; "Always")]
+    #[test_case::case(RefreshPolicy::IfStale,     Some(3600 * 24) => Some(DataSource::Cache)             ; "If stale with fresh cache")]
+    #[test_case::case(RefreshPolicy::IfStale,     Some(0)         => None                                ; "If stale with outdated cache")]
+    #[test_case::case(RefreshPolicy::IfStale,     None            => None                                ; "If stale without cache")]
+    #[test_case::case(RefreshPolicy::PreferCache, Some(0)         => Some(DataSource::OutdatedCache)     ; "Prefer cache with outdated cache")]
+    #[test_case::case(RefreshPolicy::PreferCache, None            => None                                ; "Prefer cache without cache")]
+    #[test_case::case(RefreshPolicy::Never,       Some(0)         => Some(DataSource::OutdatedCache)     ; "Never with outdated cache")]
+    #[tokio::test]
+    async fn fetch_from_cache(policy: RefreshPolicy, timestamp: Option<i64>) -> Option<DataSource> {
+        // Given
+        let cacher = build_cacher(&format!("policy-{:?}-{:?}", policy, timestamp));
+        if let Some(timestamp) = timestamp {
+            write_cache(&cacher, "U1", timestamp).await;
+        }
+
+        // When
+        let fetched = cacher.fetch_from_cache("U1", policy).await.unwrap();
+
+        // Then
+        if timestamp.is_some() {
+            std::fs::remove_dir_all(&cacher.cache_dir).unwrap();
+        }
+        fetched.map(|fetched| fetched.source)
+    }
+
+    #[test_case::case("U1234567" => true  ; "Account")]
+    #[test_case::case("all_U-1"  => true  ; "Underscore and dash")]
+    #[test_case::case("../../x"  => false ; "Parent directory")]
+    #[test_case::case("a/b"      => false ; "Separator")]
+    #[test_case::case(""         => false ; "Empty")]
+    fn cache_path(account_id: &str) -> bool {
+        build_cacher("path").cache_path(account_id).is_ok()
+    }
+
+    #[tokio::test]
+    async fn fetch_offline_without_cache() {
+        let cacher = build_cacher("offline");
+        let result = cacher.fetch_from_cache("U1", RefreshPolicy::Never).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_unversioned_cache() {
+        // Given
+        let cacher = build_cacher("unversioned");
+        tokio::fs::create_dir_all(&cacher.cache_dir).await.unwrap();
+        let legacy = r#"{"portfolio":[],"market_snapshot":{},"timestamp":"1970-01-02T00:00:00Z"}"#;
+        tokio::fs::write(cacher.cache_path("U1").unwrap(), legacy)
+            .await
+            .unwrap();
+
+        // When
+        let stock_data = cacher.show("U1").await.unwrap();
+
+        // Then
+        assert_eq!(
+            DateTime::from_timestamp(86400, 0).unwrap(),
+            stock_data.timestamp
+        );
+        assert!(stock_data.contracts.is_empty());
+        std::fs::remove_dir_all(&cacher.cache_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn write_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Given
+        let cacher = build_cacher("private");
+        let path = cacher.cache_path("U1").unwrap();
+
+        // When
+        write_cache(&cacher, "U1", 0).await;
+
+        // Then
+        let dir_mode = std::fs::metadata(&cacher.cache_dir)
+            .unwrap()
+            .permissions()
+            .mode();
+        let file_mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o700, dir_mode & 0o777);
+        assert_eq!(0o600, file_mode & 0o777);
+        std::fs::remove_dir_all(&cacher.cache_dir).unwrap();
+    }
 }
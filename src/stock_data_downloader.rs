@@ -1,15 +1,43 @@
+use crate::config::CacheConfig;
+use crate::config::HistoryConfig;
+use crate::config::PortfolioConfig;
+use crate::config::RankingConfig;
+use crate::config::SnapshotConfig;
+use crate::config::UniverseConfig;
+use crate::contract_details_downloader::ContractDetails;
 use crate::ibkr_client::PortfolioPosition;
-use anyhow::bail;
-use anyhow::Context;
+use crate::ibkr_error::IbkrError;
+use crate::position_filter::FilteredPosition;
+use crate::position_filter::PositionFilter;
+use crate::scoring_factor_extractor::ScoringFactor;
+use crate::snapshot_field;
+use crate::snapshot_field::FieldValue;
+use crate::snapshot_field::MarketDataAvailability;
+use crate::snapshot_field::Qualifier;
+use crate::snapshot_field::SnapshotField;
+use crate::snapshot_field::FIELD_ID_MARKET_DATA_AVAILABILITY;
+use crate::universe_loader::UniverseEntry;
+use crate::versioned;
+use crate::versioned::Migration;
+use crate::versioned::Versioned;
 use chrono::DateTime;
 use chrono::Utc;
+use derive_more::Display;
+use derive_more::Error;
 use derive_more::From;
+use futures::StreamExt;
+use futures::TryStreamExt;
 use serde::de::Unexpected;
 use serde::de::Visitor;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::json;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::time::Instant;
 
 #[mockall_double::double]
 use crate::ibkr_client::IbkrClient;
@@ -17,85 +45,239 @@ use crate::ibkr_client::IbkrClient;
 #[mockall_double::double]
 use crate::clock::Clock;
 
-const ASSERT_CLASS_STOCK: &str = "STK";
-const PORTFOLIO_PAGE_SIZE: usize = 30;
+#[mockall_double::double]
+use crate::history_downloader::HistoryDownloader;
+
+#[mockall_double::double]
+use crate::contract_details_downloader::ContractDetailsDownloader;
+
+#[mockall_double::double]
+use crate::universe_loader::UniverseLoader;
 
-const FIELD_ID_DIVIDEND_YIELD: i32 = 7287;
-const FIELD_ID_PE_RATIO: i32 = 7290;
-const FIELD_ID_PEMA_20: i32 = 7681;
-const FIELD_ID_PEMA_200: i32 = 7678;
-const FIELD_ID_SYMBOL: i32 = 55;
+/// Guards against a gateway that never stops returning pages.
+const MAX_PORTFOLIO_PAGES: usize = 100;
 
-#[derive(Default)]
+#[cfg_attr(test, derive(Default))]
 pub struct StockDataDownloader {
-    ibkr_client: IbkrClient,
+    ibkr_client: Rc<IbkrClient>,
+    history_downloader: HistoryDownloader,
+    contract_details_downloader: ContractDetailsDownloader,
+    universe_loader: UniverseLoader,
+    position_filter: PositionFilter,
     clock: Clock,
+    snapshot_config: SnapshotConfig,
+    snapshot_fields: Vec<&'static SnapshotField>,
+
+    /// Whether any weighted factor is computed from the bars, which are not downloaded otherwise.
+    download_history: bool,
 }
 
 impl StockDataDownloader {
-    pub async fn download_stock_data(&self, account_id: &str) -> anyhow::Result<StockData> {
-        let portfolio = self.download_portfolio(account_id).await?;
-        println!("Found {} stocks", portfolio.len());
+    pub fn new(
+        ibkr_client: Rc<IbkrClient>,
+        snapshot_config: SnapshotConfig,
+        history_config: HistoryConfig,
+        portfolio_config: PortfolioConfig,
+        universe_config: UniverseConfig,
+        cache_config: &CacheConfig,
+        ranking_config: &RankingConfig,
+    ) -> Self {
+        Self {
+            position_filter: PositionFilter::new(portfolio_config),
+            history_downloader: HistoryDownloader::new(ibkr_client.clone(), history_config),
+            contract_details_downloader: ContractDetailsDownloader::new(
+                ibkr_client.clone(),
+                cache_config,
+            ),
+            universe_loader: UniverseLoader::new(ibkr_client.clone(), universe_config),
+            ibkr_client,
+            clock: Default::default(),
+            snapshot_config,
+            snapshot_fields: snapshot_field::requested_fields(&ranking_config.weights),
+            download_history: ranking_config.needs_history(),
+        }
+    }
+
+    pub async fn download_stock_data(&self, account_id: &str) -> Result<StockData, DownloadError> {
+        let all_positions = self.download_portfolio(account_id).await?;
+        let (portfolio, filtered_positions) = self.position_filter.apply(all_positions);
+        println!(
+            "Found {} stocks, filtered out {} positions",
+            portfolio.len(),
+            filtered_positions.len()
+        );
+
+        let held_conids: HashSet<_> = portfolio.iter().map(|position| position.conid).collect();
+        let universe: Vec<_> = self
+            .universe_loader
+            .load()
+            .await
+            .into_iter()
+            .filter(|entry| !held_conids.contains(&entry.conid))
+            .collect();
+        self.download_market_data(portfolio, universe, filtered_positions)
+            .await
+    }
+
+    /// Downloads the stock data of stocks not held by any account, e.g. those found by a scanner.
+    pub async fn download_universe(
+        &self,
+        universe: Vec<UniverseEntry>,
+    ) -> Result<StockData, DownloadError> {
+        self.download_market_data(Vec::default(), universe, Vec::default())
+            .await
+    }
 
+    async fn download_market_data(
+        &self,
+        portfolio: Vec<PortfolioPosition>,
+        universe: Vec<UniverseEntry>,
+        filtered_positions: Vec<FilteredPosition>,
+    ) -> Result<StockData, DownloadError> {
         let timestamp = self.clock.now();
-        let conids: Vec<_> = portfolio.iter().map(|position| position.conid).collect();
+        let conids: Vec<_> = portfolio
+            .iter()
+            .map(|position| position.conid)
+            .chain(universe.iter().map(|entry| entry.conid))
+            .collect();
 
         if conids.is_empty() {
             let result = StockData {
+                filtered_positions,
                 timestamp,
                 ..Default::default()
             };
             return Ok(result);
         }
 
-        let market_snapshot = self.download_market_snapshot(&conids).await?;
+        let market_snapshot_raw = self.poll_market_snapshot(&conids).await?;
+        let mut incomplete_snapshots: HashMap<ContractId, Vec<IncompleteField>> = conids
+            .iter()
+            .filter_map(|conid| {
+                let missing_fields = self.missing_fields(market_snapshot_raw.get(conid));
+                (!missing_fields.is_empty()).then(|| {
+                    let fields = missing_fields.into_iter().map(IncompleteField::missing);
+                    ((*conid).into(), fields.collect())
+                })
+            })
+            .collect();
+        let mut market_snapshot_map = HashMap::default();
+        for raw in market_snapshot_raw.into_values() {
+            let (snapshot, malformed_fields) = MarketSnapshot::parse(&raw, &self.snapshot_fields)?;
+            if !malformed_fields.is_empty() {
+                incomplete_snapshots
+                    .entry(snapshot.conid.into())
+                    .or_default()
+                    .extend(malformed_fields);
+            }
+            market_snapshot_map.insert(snapshot.conid.into(), snapshot);
+        }
+        if self.download_history {
+            self.history_downloader.download(&conids).await;
+        }
+        let contracts = self.contract_details_downloader.download(&conids).await;
+
         let result = StockData {
             portfolio,
-            market_snapshot,
+            universe,
+            market_snapshot: market_snapshot_map,
+            incomplete_snapshots,
+            contracts,
+            filtered_positions,
             timestamp,
         };
         Ok(result)
     }
 
-    async fn download_market_snapshot(
+    /// Requests the snapshot again for contracts with missing fields until the deadline.
+    async fn poll_market_snapshot(
         &self,
         conids: &[i64],
-    ) -> anyhow::Result<HashMap<ContractId, MarketSnapshot>> {
-        let fields = [
-            FIELD_ID_PE_RATIO,
-            FIELD_ID_DIVIDEND_YIELD,
-            FIELD_ID_SYMBOL,
-            FIELD_ID_PEMA_20,
-            FIELD_ID_PEMA_200,
-        ];
-        let market_snapshot_raw = self.ibkr_client.market_snapshot(conids, &fields).await?;
-        let market_snapshot = market_snapshot_raw
-            .into_iter()
-            .map(TryInto::try_into)
-            .collect::<anyhow::Result<Vec<MarketSnapshot>>>()
-            .context("Failed to parse market snapshot")?;
-        let market_snapshot_map: HashMap<_, _> = market_snapshot
+    ) -> Result<HashMap<i64, HashMap<String, Value>>, DownloadError> {
+        let deadline =
+            Instant::now() + Duration::from_millis(self.snapshot_config.warm_up_deadline_millis);
+        let poll_interval = Duration::from_millis(self.snapshot_config.poll_interval_millis);
+        let mut snapshots: HashMap<i64, HashMap<String, Value>> = HashMap::default();
+        let mut pending_conids = conids.to_vec();
+        loop {
+            let market_snapshot_raw = self
+                .download_market_snapshot_in_chunks(&pending_conids)
+                .await?;
+            for raw in market_snapshot_raw {
+                let conid = extract_conid(&raw)?;
+                snapshots.entry(conid).or_default().extend(raw);
+            }
+
+            pending_conids.retain(|conid| !self.missing_fields(snapshots.get(conid)).is_empty());
+            if pending_conids.is_empty() || Instant::now() >= deadline {
+                return Ok(snapshots);
+            }
+            println!(
+                "Waiting for market data of {} contracts",
+                pending_conids.len()
+            );
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Splits the contracts into chunks so that the request URL stays within the gateway limits.
+    ///
+    /// Each chunk is a request of its own, which the client retries without the other chunks.
+    async fn download_market_snapshot_in_chunks(
+        &self,
+        conids: &[i64],
+    ) -> Result<Vec<HashMap<String, Value>>, IbkrError> {
+        let field_ids = snapshot_field::field_ids(&self.snapshot_fields);
+        futures::stream::iter(conids.chunks(self.snapshot_config.chunk_size.max(1)))
+            .map(|chunk| self.ibkr_client.market_snapshot(chunk, &field_ids))
+            .buffer_unordered(self.snapshot_config.concurrency.max(1))
+            .try_concat()
+            .await
+    }
+
+    fn missing_fields(&self, snapshot: Option<&HashMap<String, Value>>) -> Vec<i32> {
+        snapshot_field::field_ids(&self.snapshot_fields)
             .into_iter()
-            .map(|snapshot| (snapshot.conid.into(), snapshot))
-            .collect();
-        Ok(market_snapshot_map)
+            .filter(|field_id| {
+                snapshot.is_none_or(|snapshot| !snapshot.contains_key(&field_id.to_string()))
+            })
+            .collect()
     }
 
-    async fn download_portfolio(&self, account_id: &str) -> anyhow::Result<Vec<PortfolioPosition>> {
-        // Fetch the first page always
-        let mut current_page_index = 0;
-        let mut positions = self.download_portfolio_at_page(account_id, 0).await?;
+    async fn download_portfolio(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<PortfolioPosition>, IbkrError> {
+        // The gateway caches the positions and misses recent trades otherwise
+        if let Err(e) = self.ibkr_client.invalidate_portfolio(account_id).await {
+            eprintln!(
+                "Warning: Failed to refresh the positions on the gateway: {}",
+                e
+            );
+        }
 
-        let mut current_page_size = positions.len();
-        while current_page_size >= PORTFOLIO_PAGE_SIZE {
-            current_page_index += 1;
-            let next_page = self
-                .download_portfolio_at_page(account_id, current_page_index)
+        let mut positions = Vec::default();
+        let mut conids = HashSet::new();
+        for page_index in 0..MAX_PORTFOLIO_PAGES {
+            let page = self
+                .download_portfolio_at_page(account_id, page_index)
                 .await?;
-            current_page_size = next_page.len();
-            positions.extend(next_page.into_iter())
+            let mut new_positions = page
+                .into_iter()
+                .filter(|position| conids.insert(position.conid))
+                .peekable();
+
+            // Ends at an empty page, or at a page of known positions if the gateway repeats itself
+            if new_positions.peek().is_none() {
+                return Ok(positions);
+            }
+            positions.extend(new_positions);
         }
 
+        eprintln!(
+            "Warning: Stopped reading the portfolio after {} pages",
+            MAX_PORTFOLIO_PAGES
+        );
         Ok(positions)
     }
 
@@ -103,97 +285,223 @@ impl StockDataDownloader {
         &self,
         account_id: &str,
         page_index: usize,
-    ) -> anyhow::Result<Vec<PortfolioPosition>> {
-        let mut portfolio = self.ibkr_client.portfolio(account_id, page_index).await?;
-
-        // Filter out non-stock entries because IBKR somehow keeps showing forex in my portfolio.
-        // Filter out entries with 0 position because IBKR still include stocks I recently sold.
-        portfolio.retain(|entry| entry.assetClass == ASSERT_CLASS_STOCK && entry.position != 0.0);
-
-        Ok(portfolio)
+    ) -> Result<Vec<PortfolioPosition>, IbkrError> {
+        self.ibkr_client.portfolio(account_id, page_index).await
     }
 }
 
 #[derive(Deserialize, Serialize, Default, PartialEq, Debug)]
 pub struct StockData {
     pub portfolio: Vec<PortfolioPosition>,
+
+    /// Stocks not held, ranked alongside the portfolio.
+    pub universe: Vec<UniverseEntry>,
+
     pub market_snapshot: HashMap<ContractId, MarketSnapshot>,
+
+    /// Snapshot fields the gateway never filled in before the deadline or filled in malformed.
+    pub incomplete_snapshots: HashMap<ContractId, Vec<IncompleteField>>,
+
+    pub contracts: HashMap<ContractId, ContractDetails>,
+
+    /// Positions left out of the portfolio.
+    pub filtered_positions: Vec<FilteredPosition>,
+
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct MarketSnapshot {
-    pub conid: i64,
-    pub pe_ratio: Option<f64>,
-    pub dividend_yield: Option<f64>,
-    pub pema_20: Option<f64>,
-    pub pema_200: Option<f64>,
+impl Versioned for StockData {
+    const NAME: &'static str = "Stock data";
+    const MIGRATIONS: &'static [Migration] = &[migrate_stock_data_v1];
+}
+
+/// Version 1, written before the envelope existed, had a property for each snapshot field
+/// and none of the fields added since.
+fn migrate_stock_data_v1(value: Value) -> anyhow::Result<Value> {
+    let mut value = versioned::fill_missing_fields(
+        value,
+        &[
+            ("universe", json!([])),
+            ("incomplete_snapshots", json!({})),
+            ("contracts", json!({})),
+            ("filtered_positions", json!([])),
+        ],
+    )?;
+    let snapshots = value
+        .get_mut("market_snapshot")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow::anyhow!("Missing market snapshot"))?;
+    for snapshot in snapshots.values_mut() {
+        let object = snapshot
+            .as_object_mut()
+            .ok_or_else(|| anyhow::anyhow!("Expected a JSON object"))?;
+        let mut fields = serde_json::Map::new();
+        for (property, factor) in [
+            ("pe_ratio", "pe-ratio"),
+            ("dividend_yield", "dividend-yield"),
+            ("pema_20", "snapshot-price-ema20-change"),
+            ("pema_200", "snapshot-price-ema200-change"),
+        ] {
+            if let Some(field) = object.remove(property).filter(|field| !field.is_null()) {
+                fields.insert(factor.into(), field);
+            }
+        }
+        object.insert("fields".into(), fields.into());
+        *snapshot = versioned::fill_missing_fields(
+            snapshot.take(),
+            &[("qualifiers", json!({})), ("availability", Value::Null)],
+        )?;
+    }
+    Ok(value)
 }
 
-impl TryFrom<HashMap<String, Value>> for MarketSnapshot {
-    type Error = anyhow::Error;
+impl StockData {
+    /// Combines the holdings of multiple accounts, adding up the positions of the same contract.
+    pub fn merge<'a>(all_stock_data: impl IntoIterator<Item = &'a StockData>) -> Self {
+        let mut result = StockData::default();
+        let mut timestamp: Option<DateTime<Utc>> = None;
+        for stock_data in all_stock_data {
+            for position in &stock_data.portfolio {
+                if let Some(existing) = result
+                    .portfolio
+                    .iter_mut()
+                    .find(|existing| existing.conid == position.conid)
+                {
+                    existing.add(position);
+                } else {
+                    result.portfolio.push(position.clone());
+                }
+            }
+            result.market_snapshot.extend(
+                stock_data
+                    .market_snapshot
+                    .iter()
+                    .map(|(conid, snapshot)| (*conid, snapshot.clone())),
+            );
+            result.incomplete_snapshots.extend(
+                stock_data
+                    .incomplete_snapshots
+                    .iter()
+                    .map(|(conid, field_ids)| (*conid, field_ids.clone())),
+            );
+            result.contracts.extend(
+                stock_data
+                    .contracts
+                    .iter()
+                    .map(|(conid, details)| (*conid, details.clone())),
+            );
+            result
+                .filtered_positions
+                .extend(stock_data.filtered_positions.iter().cloned());
+            result.universe.extend(stock_data.universe.iter().cloned());
+
+            // The merged data is only as fresh as its oldest part
+            timestamp =
+                Some(timestamp.map_or(stock_data.timestamp, |t| t.min(stock_data.timestamp)));
+        }
+        result.timestamp = timestamp.unwrap_or_default();
 
-    fn try_from(value: HashMap<String, Value>) -> Result<Self, Self::Error> {
-        let result = Self {
-            conid: extract_conid(&value)?,
-            pe_ratio: extract_pe_ratio(&value)?,
-            dividend_yield: extract_dividend_yield(&value)?,
-            pema_20: extract_pema_20(&value)?,
-            pema_200: extract_pema_200(&value)?,
-        };
-        Ok(result)
+        // A stock is new only if no account holds it
+        let mut conids: HashSet<_> = result
+            .portfolio
+            .iter()
+            .map(|position| position.conid)
+            .collect();
+        result.universe.retain(|entry| conids.insert(entry.conid));
+        result
     }
 }
 
-fn extract_pe_ratio(data: &HashMap<String, Value>) -> anyhow::Result<Option<f64>> {
-    data.get(&FIELD_ID_PE_RATIO.to_string())
-        .map(unwrap_string_value)
-        .transpose()?
-        .map(|raw| raw.parse())
-        .transpose()
-        .context("Failed to parse P/E")
+/// Snapshot field that is not available for ranking.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct IncompleteField {
+    pub field_id: i32,
+
+    /// Value the gateway filled in but failed to parse, or `None` if it never filled in any.
+    pub malformed_value: Option<String>,
 }
 
-fn extract_conid(data: &HashMap<String, Value>) -> anyhow::Result<i64> {
-    data.get("conid")
-        .ok_or_else(|| anyhow::anyhow!("No `conid`"))?
-        .as_i64()
-        .ok_or_else(|| anyhow::anyhow!("`conid` does not contain an `i64`"))
+impl IncompleteField {
+    pub fn missing(field_id: i32) -> Self {
+        Self {
+            field_id,
+            malformed_value: None,
+        }
+    }
 }
 
-fn extract_dividend_yield(data: &HashMap<String, Value>) -> anyhow::Result<Option<f64>> {
-    data.get(&FIELD_ID_DIVIDEND_YIELD.to_string())
-        .map(extract_percentage)
-        .transpose()
-        .context("Failed to parse P/E")
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct MarketSnapshot {
+    pub conid: i64,
+
+    /// Values of the requested fields the gateway filled in.
+    pub fields: HashMap<ScoringFactor, f64>,
+
+    /// What the prefixes of some of the [Self::fields] tell, e.g. a price at the previous close.
+    pub qualifiers: HashMap<ScoringFactor, Qualifier>,
+
+    pub availability: Option<MarketDataAvailability>,
 }
 
-fn extract_pema_20(data: &HashMap<String, Value>) -> anyhow::Result<Option<f64>> {
-    data.get(&FIELD_ID_PEMA_20.to_string())
-        .map(extract_percentage)
-        .transpose()
-        .context("Failed to parse Price to EMA(20) change")
+impl MarketSnapshot {
+    /// Parses the requested `fields` out of a raw snapshot, also returning the malformed ones.
+    ///
+    /// A malformed field counts as unavailable rather than failing the whole download.
+    fn parse(
+        value: &HashMap<String, Value>,
+        fields: &[&SnapshotField],
+    ) -> Result<(Self, Vec<IncompleteField>), DownloadError> {
+        let mut result = Self {
+            conid: extract_conid(value)?,
+            availability: value
+                .get(&FIELD_ID_MARKET_DATA_AVAILABILITY.to_string())
+                .and_then(MarketDataAvailability::parse),
+            ..Default::default()
+        };
+        let mut malformed_fields = Vec::default();
+        for field in fields {
+            let Some(raw) = value.get(&field.id.to_string()) else {
+                continue;
+            };
+            match field.parser.parse(raw) {
+                Some(FieldValue::Number { value, qualifier }) => {
+                    result.fields.insert(field.factor, value);
+                    if let Some(qualifier) = qualifier {
+                        result.qualifiers.insert(field.factor, qualifier);
+                    }
+                }
+                Some(FieldValue::Unavailable) => {}
+                None => malformed_fields.push(IncompleteField {
+                    field_id: field.id,
+                    malformed_value: Some(raw.to_string()),
+                }),
+            }
+        }
+        Ok((result, malformed_fields))
+    }
 }
 
-fn extract_pema_200(data: &HashMap<String, Value>) -> anyhow::Result<Option<f64>> {
-    data.get(&FIELD_ID_PEMA_200.to_string())
-        .map(extract_percentage)
-        .transpose()
-        .context("Failed to parse Price to EMA(200) change")
+fn extract_conid(data: &HashMap<String, Value>) -> Result<i64, DownloadError> {
+    data.get("conid")
+        .and_then(Value::as_i64)
+        .ok_or_else(|| DownloadError::MissingContractId {
+            snapshot: serde_json::to_string(data).unwrap_or_default(),
+        })
 }
 
-fn extract_percentage(data: &Value) -> anyhow::Result<f64> {
-    let text = unwrap_string_value(data)?;
-    let without_percentage_symbol = text.trim_end_matches('%').to_string();
-    let number = without_percentage_symbol.parse::<f64>()?;
-    Ok(number / 100.0)
+/// Failure in downloading [StockData].
+#[derive(Debug, Display, Error)]
+pub enum DownloadError {
+    #[display(fmt = "Failed to query IBKR")]
+    Ibkr { source: IbkrError },
+
+    #[display(fmt = "Market snapshot without a contract ID: {}", snapshot)]
+    MissingContractId { snapshot: String },
 }
 
-fn unwrap_string_value(value: &Value) -> anyhow::Result<String> {
-    if let Value::String(text) = value {
-        Ok(text.clone())
-    } else {
-        bail!("Expects a string")
+impl From<IbkrError> for DownloadError {
+    fn from(source: IbkrError) -> Self {
+        Self::Ibkr { source }
     }
 }
 
@@ -244,18 +552,51 @@ impl<'de> Visitor<'de> for ContractIdVisitor {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::position_filter::FilterReason;
+    use itertools::Itertools;
     use mockall::predicate::*;
+    use std::ops::Range;
+
+    const ASSERT_CLASS_STOCK: &str = "STK";
 
     #[tokio::test]
     async fn download_stock_data() {
-        let portfolio = vec![PortfolioPosition {
+        let stock = PortfolioPosition {
             conid: 100,
             assetClass: ASSERT_CLASS_STOCK.into(),
             position: 1.0,
             ..Default::default()
-        }];
+        };
+        let forex = PortfolioPosition {
+            conid: 200,
+            assetClass: "CASH".into(),
+            position: 1.0,
+            ..Default::default()
+        };
+        let portfolio = vec![stock.clone(), forex.clone()];
+        let new_stock = UniverseEntry {
+            conid: 300,
+            ticker: "NEW".into(),
+        };
+        let universe = vec![
+            UniverseEntry {
+                conid: 100,
+                ticker: "HELD".into(),
+            },
+            new_stock.clone(),
+        ];
         let expected_stock_data = StockData {
-            portfolio: portfolio.clone(),
+            portfolio: vec![stock],
+            universe: vec![new_stock],
+            incomplete_snapshots: [
+                (100.into(), missing_fields(&snapshot_field::field_ids(&[]))),
+                (300.into(), missing_fields(&snapshot_field::field_ids(&[]))),
+            ]
+            .into(),
+            filtered_positions: vec![FilteredPosition {
+                position: forex,
+                reason: FilterReason::AssetClass("CASH".into()),
+            }],
             ..Default::default()
         };
 
@@ -265,15 +606,44 @@ mod test {
             .return_const(<DateTime<Utc> as Default>::default());
 
         let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_invalidate_portfolio()
+            .returning(|_| Ok(()));
         ibkr_client
             .expect_portfolio()
+            .with(always(), eq(0))
             .return_once(move |_, _| Ok(portfolio));
+        ibkr_client
+            .expect_portfolio()
+            .with(always(), eq(1))
+            .returning(|_, _| Ok(vec![]));
         ibkr_client
             .expect_market_snapshot()
-            .with(eq([100_i64]), always())
+            .with(eq([100_i64, 300]), always())
             .return_once(move |_, _| Ok(Default::default()));
 
-        let downloader = StockDataDownloader { ibkr_client, clock };
+        let mut history_downloader = HistoryDownloader::default();
+        history_downloader
+            .expect_download()
+            .with(eq([100_i64, 300]))
+            .return_const(());
+        let mut contract_details_downloader = ContractDetailsDownloader::default();
+        contract_details_downloader
+            .expect_download()
+            .with(eq([100_i64, 300]))
+            .return_const(HashMap::default());
+        let mut universe_loader = UniverseLoader::default();
+        universe_loader.expect_load().return_const(universe);
+        let downloader = StockDataDownloader {
+            ibkr_client: ibkr_client.into(),
+            history_downloader,
+            contract_details_downloader,
+            universe_loader,
+            clock,
+            snapshot_config: no_warm_up(),
+            download_history: true,
+            ..Default::default()
+        };
 
         // When
         let actual_stock_data = downloader.download_stock_data("").await.unwrap();
@@ -282,6 +652,153 @@ mod test {
         assert_eq!(expected_stock_data, actual_stock_data);
     }
 
+    #[tokio::test]
+    async fn poll_market_snapshot_until_filled_in() {
+        // Given
+        let subscribed: HashMap<_, _> = [("conid".to_string(), 1.into())].into();
+        let filled_in: HashMap<_, _> = snapshot_field::field_ids(&[])
+            .iter()
+            .map(|field_id| (field_id.to_string(), "1".into()))
+            .chain([("conid".to_string(), 1.into())])
+            .collect();
+        let expected_snapshots: HashMap<_, _> = [(1, filled_in.clone())].into();
+
+        let mut ibkr_client = IbkrClient::default();
+        let mut sequence = mockall::Sequence::new();
+        ibkr_client
+            .expect_market_snapshot()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .with(eq([1_i64, 2]), always())
+            .return_once(move |_, _| Ok(vec![subscribed]));
+        ibkr_client
+            .expect_market_snapshot()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .with(eq([1_i64, 2]), always())
+            .return_once(move |_, _| Ok(vec![filled_in]));
+        ibkr_client
+            .expect_market_snapshot()
+            .with(eq([2_i64]), always())
+            .returning(|_, _| Ok(vec![]));
+
+        let downloader = StockDataDownloader {
+            ibkr_client: ibkr_client.into(),
+            snapshot_config: SnapshotConfig {
+                warm_up_deadline_millis: 50,
+                poll_interval_millis: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // When
+        let actual_snapshots = downloader.poll_market_snapshot(&[1, 2]).await.unwrap();
+
+        // Then
+        assert_eq!(expected_snapshots, actual_snapshots);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_market_snapshot_in_chunks() {
+        // Given
+        let snapshot_of =
+            |conid: i64| -> HashMap<String, Value> { [("conid".to_string(), conid.into())].into() };
+
+        let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_market_snapshot()
+            .with(eq([1_i64, 2]), always())
+            .times(1)
+            .returning(move |_, _| Ok(vec![snapshot_of(1), snapshot_of(2)]));
+        ibkr_client
+            .expect_market_snapshot()
+            .with(eq([3_i64, 4]), always())
+            .times(1)
+            .returning(move |_, _| Ok(vec![snapshot_of(3), snapshot_of(4)]));
+
+        let downloader = StockDataDownloader {
+            ibkr_client: ibkr_client.into(),
+            snapshot_config: SnapshotConfig {
+                chunk_size: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // When
+        let actual_conids: Vec<_> = downloader
+            .download_market_snapshot_in_chunks(&[1, 2, 3, 4])
+            .await
+            .unwrap()
+            .iter()
+            .map(|snapshot| extract_conid(snapshot).unwrap())
+            .sorted()
+            .collect();
+
+        // Then
+        assert_eq!(vec![1, 2, 3, 4], actual_conids);
+    }
+
+    #[tokio::test]
+    async fn download_market_snapshot_chunk_failing() {
+        // Given
+        let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_market_snapshot()
+            .times(1)
+            .returning(|_, _| Err(internal_server_error()));
+
+        let downloader = StockDataDownloader {
+            ibkr_client: ibkr_client.into(),
+            ..Default::default()
+        };
+
+        // When
+        let result = downloader.download_market_snapshot_in_chunks(&[1]).await;
+
+        // Then
+        assert!(matches!(result, Err(IbkrError::Http { .. })));
+    }
+
+    #[test]
+    fn merge_stock_data() {
+        // Given
+        let position = |conid: i64, position: f64| PortfolioPosition {
+            conid,
+            position,
+            ..Default::default()
+        };
+        let entry = |conid: i64| UniverseEntry {
+            conid,
+            ticker: Default::default(),
+        };
+        let stock_data_1 = StockData {
+            portfolio: vec![position(1, 1.0), position(2, 2.0)],
+            universe: vec![entry(3), entry(4)],
+            timestamp: DateTime::from_timestamp(200, 0).unwrap(),
+            ..Default::default()
+        };
+        let stock_data_2 = StockData {
+            portfolio: vec![position(2, 3.0), position(3, 4.0)],
+            universe: vec![entry(4)],
+            timestamp: DateTime::from_timestamp(100, 0).unwrap(),
+            ..Default::default()
+        };
+        let expected_stock_data = StockData {
+            portfolio: vec![position(1, 1.0), position(2, 5.0), position(3, 4.0)],
+            universe: vec![entry(4)],
+            timestamp: DateTime::from_timestamp(100, 0).unwrap(),
+            ..Default::default()
+        };
+
+        // When
+        let actual_stock_data = StockData::merge([&stock_data_1, &stock_data_2]);
+
+        // Then
+        assert_eq!(expected_stock_data, actual_stock_data);
+    }
+
     #[tokio::test]
     async fn download_stock_data_from_no_portfolio() {
         let mut clock = Clock::default();
@@ -290,9 +807,19 @@ mod test {
             .return_const(<DateTime<Utc> as Default>::default());
 
         let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_invalidate_portfolio()
+            .returning(|_| Ok(()));
         ibkr_client.expect_portfolio().returning(|_, _| Ok(vec![]));
+        let mut universe_loader = UniverseLoader::default();
+        universe_loader.expect_load().return_const(Vec::default());
 
-        let downloader = StockDataDownloader { ibkr_client, clock };
+        let downloader = StockDataDownloader {
+            ibkr_client: ibkr_client.into(),
+            universe_loader,
+            clock,
+            ..Default::default()
+        };
 
         // When
         let actual_stock_data = downloader.download_stock_data("").await.unwrap();
@@ -303,26 +830,32 @@ mod test {
 
     #[tokio::test]
     async fn download_portfolio_from_multiple_pages() {
-        let entries_at_last_page = 5;
-        let expected_portfolio =
-            build_portfolio_with_n_entries(PORTFOLIO_PAGE_SIZE * 2 + entries_at_last_page);
+        let expected_portfolio = build_portfolio(0..65);
 
         let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_invalidate_portfolio()
+            .times(1)
+            .returning(|_| Ok(()));
         ibkr_client
             .expect_portfolio()
             .with(always(), eq(0))
-            .returning(|_, _| Ok(build_portfolio_with_n_entries(PORTFOLIO_PAGE_SIZE)));
+            .returning(|_, _| Ok(build_portfolio(0..30)));
         ibkr_client
             .expect_portfolio()
             .with(always(), eq(1))
-            .returning(|_, _| Ok(build_portfolio_with_n_entries(PORTFOLIO_PAGE_SIZE)));
+            .returning(|_, _| Ok(build_portfolio(30..60)));
         ibkr_client
             .expect_portfolio()
             .with(always(), eq(2))
-            .returning(move |_, _| Ok(build_portfolio_with_n_entries(entries_at_last_page)));
+            .returning(|_, _| Ok(build_portfolio(60..65)));
+        ibkr_client
+            .expect_portfolio()
+            .with(always(), eq(3))
+            .returning(|_, _| Ok(vec![]));
 
         let downloader = StockDataDownloader {
-            ibkr_client,
+            ibkr_client: ibkr_client.into(),
             ..Default::default()
         };
 
@@ -334,18 +867,28 @@ mod test {
     }
 
     #[tokio::test]
-    async fn download_portfolio_from_1_page() {
-        let entries_at_last_page = 3;
-        let expected_portfolio = build_portfolio_with_n_entries(entries_at_last_page);
+    async fn download_portfolio_with_overlapping_pages() {
+        let expected_portfolio = build_portfolio(0..5);
 
         let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_invalidate_portfolio()
+            .returning(|_| Err(internal_server_error()));
         ibkr_client
             .expect_portfolio()
             .with(always(), eq(0))
-            .returning(move |_, _| Ok(build_portfolio_with_n_entries(entries_at_last_page)));
+            .returning(|_, _| Ok(build_portfolio(0..3)));
+        ibkr_client
+            .expect_portfolio()
+            .with(always(), eq(1))
+            .returning(|_, _| Ok(build_portfolio(2..5)));
+        ibkr_client
+            .expect_portfolio()
+            .with(always(), eq(2))
+            .returning(|_, _| Ok(build_portfolio(2..5)));
 
         let downloader = StockDataDownloader {
-            ibkr_client,
+            ibkr_client: ibkr_client.into(),
             ..Default::default()
         };
 
@@ -357,87 +900,183 @@ mod test {
     }
 
     #[tokio::test]
-    async fn download_portfolio_at_page() {
-        let raw_portfolio = vec![
-            PortfolioPosition {
-                conid: 0,
-                ticker: "TICKER".into(),
-                position: 10.0,
-                assetClass: ASSERT_CLASS_STOCK.into(),
-            },
-            PortfolioPosition {
-                conid: 0,
-                ticker: "SOLD".into(),
-                position: 0.0,
-                assetClass: ASSERT_CLASS_STOCK.into(),
-            },
-            PortfolioPosition {
-                conid: 0,
-                ticker: "CZK".into(),
-                position: 10.0,
-                assetClass: "FX".into(),
-            },
-        ];
-        let expected_portfolio = vec![PortfolioPosition {
-            conid: 0,
-            ticker: "TICKER".into(),
-            position: 10.0,
-            assetClass: ASSERT_CLASS_STOCK.into(),
-        }];
-
+    async fn download_portfolio_from_endless_pages() {
         let mut ibkr_client = IbkrClient::default();
+        ibkr_client
+            .expect_invalidate_portfolio()
+            .returning(|_| Ok(()));
         ibkr_client
             .expect_portfolio()
-            .return_once(move |_, _| Ok(raw_portfolio));
+            .times(MAX_PORTFOLIO_PAGES)
+            .returning(|_, page_index| {
+                let conid = page_index as i64;
+                Ok(build_portfolio(conid..conid + 1))
+            });
 
         let downloader = StockDataDownloader {
-            ibkr_client,
+            ibkr_client: ibkr_client.into(),
             ..Default::default()
         };
 
         // When
-        let actual_portfolio = downloader.download_portfolio_at_page("", 0).await.unwrap();
+        let actual_portfolio = downloader.download_portfolio("").await.unwrap();
 
         // Then
-        assert_eq!(expected_portfolio, actual_portfolio);
+        assert_eq!(MAX_PORTFOLIO_PAGES, actual_portfolio.len());
+    }
+
+    #[test]
+    fn parse_market_snapshot() {
+        // Given
+        let raw: HashMap<_, _> = [
+            ("conid".into(), 1.into()),
+            ("7290".into(), "2".into()),
+            ("7287".into(), "3%".into()),
+            ("7681".into(), "-4%".into()),
+            ("unknown".into(), "5%".into()),
+        ]
+        .into();
+        let expected_market_snapshot = MarketSnapshot {
+            conid: 1,
+            fields: [
+                (ScoringFactor::PeRatio, 2.0),
+                (ScoringFactor::DividendYield, 0.03),
+                (ScoringFactor::SnapshotPriceEma20Change, -0.04),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        // When
+        let actual_market_snapshot =
+            MarketSnapshot::parse(&raw, &snapshot_field::requested_fields(&HashMap::default()))
+                .unwrap();
+
+        // Then
+        assert_eq!((expected_market_snapshot, vec![]), actual_market_snapshot);
+    }
+
+    #[test]
+    fn parse_market_snapshot_qualified_fields() {
+        // Given
+        let raw: HashMap<_, _> = [
+            ("conid".into(), 1.into()),
+            ("6509".into(), "DPB".into()),
+            ("31".into(), "C123.5".into()),
+            ("7289".into(), "2.5B".into()),
+            ("7290".into(), "N/A".into()),
+            ("7287".into(), "-".into()),
+        ]
+        .into();
+        let expected_market_snapshot = MarketSnapshot {
+            conid: 1,
+            fields: [
+                (ScoringFactor::LastPrice, 123.5),
+                (ScoringFactor::MarketCap, 2.5e9),
+            ]
+            .into(),
+            qualifiers: [(ScoringFactor::LastPrice, Qualifier::Close)].into(),
+            availability: Some(MarketDataAvailability::Delayed),
+        };
+
+        // When
+        let actual_market_snapshot = MarketSnapshot::parse(&raw, &all_fields()).unwrap();
+
+        // Then
+        assert_eq!((expected_market_snapshot, vec![]), actual_market_snapshot);
     }
 
     #[test]
-    fn market_snapshot_try_from() {
+    fn parse_market_snapshot_malformed_field() {
         // Given
         let raw: HashMap<_, _> = [
             ("conid".into(), 1.into()),
-            (FIELD_ID_PE_RATIO.to_string(), "2".into()),
-            (FIELD_ID_DIVIDEND_YIELD.to_string(), "3%".into()),
-            (FIELD_ID_PEMA_20.to_string(), "-4%".into()),
-            (FIELD_ID_PEMA_200.to_string(), "5%".into()),
+            ("7290".into(), "2".into()),
+            ("7287".into(), "3%%".into()),
         ]
         .into();
         let expected_market_snapshot = MarketSnapshot {
             conid: 1,
-            pe_ratio: 2.0.into(),
-            dividend_yield: 0.03.into(),
-            pema_20: (-0.04).into(),
-            pema_200: 0.05.into(),
+            fields: [(ScoringFactor::PeRatio, 2.0)].into(),
+            ..Default::default()
         };
+        let expected_malformed_fields = vec![IncompleteField {
+            field_id: 7287,
+            malformed_value: Some(r#""3%%""#.into()),
+        }];
 
         // When
-        let actual_market_snapshot: MarketSnapshot = raw.try_into().unwrap();
+        let actual_market_snapshot =
+            MarketSnapshot::parse(&raw, &snapshot_field::requested_fields(&HashMap::default()))
+                .unwrap();
 
         // Then
-        assert_eq!(expected_market_snapshot, actual_market_snapshot);
+        assert_eq!(
+            (expected_market_snapshot, expected_malformed_fields),
+            actual_market_snapshot
+        );
     }
 
-    #[test_case::case(FIELD_ID_PE_RATIO.to_string() => Some(123.0))]
-    #[test_case::case("unknown".into()              => None)]
-    fn extract_pe_ratio(field_id: String) -> Option<f64> {
-        let raw: HashMap<_, _> = [(field_id, "123".into())].into();
-        super::extract_pe_ratio(&raw).unwrap()
+    #[test]
+    fn migrate_unversioned_stock_data() {
+        // Given
+        let stock_data = r#"{"portfolio":[],"timestamp":"1970-01-01T00:00:00Z",
+            "market_snapshot":{"1":{"conid":1,"pe_ratio":2.0,"dividend_yield":null,"pema_20":-0.04,"pema_200":0.05}}}"#;
+        let expected_market_snapshot = MarketSnapshot {
+            conid: 1,
+            fields: [
+                (ScoringFactor::PeRatio, 2.0),
+                (ScoringFactor::SnapshotPriceEma20Change, -0.04),
+                (ScoringFactor::SnapshotPriceEma200Change, 0.05),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        // When
+        let stock_data: StockData = versioned::from_str(stock_data).unwrap();
+
+        // Then
+        assert_eq!(
+            expected_market_snapshot,
+            stock_data.market_snapshot[&1.into()]
+        );
+        assert!(stock_data.universe.is_empty());
+    }
+
+    fn internal_server_error() -> IbkrError {
+        IbkrError::Http {
+            endpoint: "iserver/marketdata/snapshot".into(),
+            status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            body: Default::default(),
+        }
+    }
+
+    /// Every field of the registry, as if all were weighted.
+    fn all_fields() -> Vec<&'static SnapshotField> {
+        snapshot_field::SNAPSHOT_FIELDS.iter().collect()
+    }
+
+    fn missing_fields(field_ids: &[i32]) -> Vec<IncompleteField> {
+        field_ids
+            .iter()
+            .copied()
+            .map(IncompleteField::missing)
+            .collect()
+    }
+
+    fn no_warm_up() -> SnapshotConfig {
+        SnapshotConfig {
+            warm_up_deadline_millis: 0,
+            poll_interval_millis: 0,
+            ..Default::default()
+        }
     }
 
-    fn build_portfolio_with_n_entries(n: usize) -> Vec<PortfolioPosition> {
-        (0..n)
-            .map(|_| PortfolioPosition {
+    fn build_portfolio(conids: Range<i64>) -> Vec<PortfolioPosition> {
+        conids
+            .map(|conid| PortfolioPosition {
+                conid,
                 position: 1.0,
                 assetClass: ASSERT_CLASS_STOCK.into(),
                 ..Default::default()
@@ -6,7 +6,9 @@ mod positive_least_winning_ranker;
 use self::negative_least_winning_ranker::NegativeLeastWinningRanker;
 use self::positive_greatest_winning_ranker::PositiveGreatestWinningRanker;
 use self::positive_least_winning_ranker::PositiveLeastWinningRanker;
+use crate::config::RankingConfig;
 use crate::scoring_factor_extractor::ScoringFactor;
+use crate::snapshot_field::SNAPSHOT_FIELDS;
 use crate::stock_candidates::StockCandidates;
 use derive_more::Add;
 use derive_more::Display;
@@ -23,33 +25,56 @@ pub struct StockRanker {
 
 impl Default for StockRanker {
     fn default() -> Self {
+        #[rustfmt::skip]
+        let indicators = [
+            // Technical indicators only count once configured
+            (ScoringFactor::PriceSmaChange(50), Ranking::of_moving_average(50), 0.0),
+            (ScoringFactor::PriceSmaChange(200), Ranking::of_moving_average(200), 0.0),
+            (ScoringFactor::PriceEmaChange(50), Ranking::of_moving_average(50), 0.0),
+            // Oversold stocks win
+            (ScoringFactor::Rsi14, Ranking::PositiveLeast, 0.0),
+            (ScoringFactor::MacdHistogram, Ranking::PositiveGreatest, 0.0),
+            (ScoringFactor::BollingerPercentB, Ranking::PositiveLeast, 0.0),
+            (ScoringFactor::DrawdownFrom52WeekHigh, Ranking::NegativeLeast, 0.0),
+            (ScoringFactor::RealizedVolatility, Ranking::PositiveLeast, 0.0),
+        ];
+        let snapshot_fields = SNAPSHOT_FIELDS
+            .iter()
+            .filter_map(|field| Some((field.factor, field.ranking?, field.weight)));
+        let (rankers, factor_weight) = snapshot_fields
+            .chain(indicators)
+            .map(|(factor, ranking, weight)| (ranking.ranker(factor), (factor, weight)))
+            .unzip();
         Self {
-            rankers: vec![
-                Box::new(PositiveGreatestWinningRanker::new(
-                    ScoringFactor::DividendYield,
-                )),
-                Box::new(PositiveLeastWinningRanker::new(ScoringFactor::PeRatio)),
-                Box::new(NegativeLeastWinningRanker::new(
-                    ScoringFactor::PriceEma20Change,
-                )),
-                Box::new(PositiveGreatestWinningRanker::new(
-                    ScoringFactor::PriceEma200Change,
-                )),
-            ],
-            factor_weight: HashMap::from([
-                // Half of my stocks don't pay dividend, and even they do, it's not a significant
-                // income. Let's not make it too pronounced in to decision making.
-                (ScoringFactor::DividendYield, 1.0),
-                // P/E ratio of some companies (especially PAH3, merely 3!) feel artificial.
-                (ScoringFactor::PeRatio, 0.0),
-                (ScoringFactor::PriceEma20Change, 4.0),
-                (ScoringFactor::PriceEma200Change, 5.0),
-            ]),
+            rankers,
+            factor_weight,
         }
     }
 }
 
 impl StockRanker {
+    /// Uses the default weights unless overridden in the config.
+    pub fn new(config: &RankingConfig) -> Self {
+        let mut result = Self::default();
+        for factor in config.weights.keys() {
+            if let Some(length) = factor.moving_average_length() {
+                if !result.factor_weight.contains_key(factor) {
+                    let ranking = Ranking::of_moving_average(length);
+                    result.rankers.push(ranking.ranker(*factor));
+                    result.factor_weight.insert(*factor, 0.0);
+                }
+            }
+            if !result.factor_weight.contains_key(factor) {
+                eprintln!(
+                    "Warning: Ignoring the weight of {}, which is not ranked",
+                    factor
+                );
+            }
+        }
+        result.factor_weight.extend(config.weights.clone());
+        result
+    }
+
     pub fn rank(&self, candidates: &StockCandidates) -> HashMap<Ticker, Score> {
         self.rankers
             .iter()
@@ -74,6 +99,39 @@ impl StockRanker {
     }
 }
 
+/// Which stocks win in a factor.
+#[derive(Clone, Copy, Debug)]
+pub enum Ranking {
+    /// Only positive values count, and the greatest wins.
+    PositiveGreatest,
+
+    /// Only positive values count, and the least wins.
+    PositiveLeast,
+
+    /// Only negative values count, and the least (i.e. the greatest drop) wins.
+    NegativeLeast,
+}
+
+impl Ranking {
+    /// Like the EMAs of IBKR, a dip below a short average wins,
+    /// while a long average wins when the price trends above it.
+    fn of_moving_average(length: u32) -> Self {
+        if length < 100 {
+            Self::NegativeLeast
+        } else {
+            Self::PositiveGreatest
+        }
+    }
+
+    fn ranker(self, factor: ScoringFactor) -> Box<dyn FactorRanker> {
+        match self {
+            Self::PositiveGreatest => Box::new(PositiveGreatestWinningRanker::new(factor)),
+            Self::PositiveLeast => Box::new(PositiveLeastWinningRanker::new(factor)),
+            Self::NegativeLeast => Box::new(NegativeLeastWinningRanker::new(factor)),
+        }
+    }
+}
+
 #[mockall::automock]
 trait FactorRanker {
     fn rank(&self, candidates: &StockCandidates) -> HashMap<Ticker, Score>;
@@ -148,4 +206,58 @@ mod test {
         // Then
         assert_eq!(expected_scores, actual_scores);
     }
+
+    #[test]
+    fn override_weights() {
+        // Given
+        let config = RankingConfig {
+            weights: [(ScoringFactor::Rsi14, 2.0)].into(),
+        };
+
+        // When
+        let ranker = StockRanker::new(&config);
+
+        // Then
+        assert_eq!(2.0, ranker.factor_weight[&ScoringFactor::Rsi14]);
+        assert_eq!(
+            5.0,
+            ranker.factor_weight[&ScoringFactor::SnapshotPriceEma200Change]
+        );
+    }
+
+    #[test]
+    fn rank_configured_moving_average() {
+        // Given
+        let config = RankingConfig {
+            weights: [(ScoringFactor::PriceEmaChange(150), 2.0)].into(),
+        };
+
+        // When
+        let ranker = StockRanker::new(&config);
+
+        // Then
+        assert_eq!(
+            2.0,
+            ranker.factor_weight[&ScoringFactor::PriceEmaChange(150)]
+        );
+        assert!(ranker
+            .rankers
+            .iter()
+            .any(|factor_ranker| factor_ranker.get_factor() == ScoringFactor::PriceEmaChange(150)));
+    }
+
+    #[test]
+    fn rank_all_snapshot_fields() {
+        let ranker = StockRanker::default();
+        for field in SNAPSHOT_FIELDS
+            .iter()
+            .filter(|field| field.ranking.is_some())
+        {
+            assert_eq!(field.weight, ranker.factor_weight[&field.factor]);
+            assert!(ranker
+                .rankers
+                .iter()
+                .any(|factor_ranker| factor_ranker.get_factor() == field.factor));
+        }
+    }
 }
@@ -51,19 +51,25 @@ mod test {
         let stock_candidates: StockCandidates = [
             (
                 "A",
-                HashMap::from([(ScoringFactor::PriceEma20Change, Notional::from(-1.0))]),
+                HashMap::from([(
+                    ScoringFactor::SnapshotPriceEma20Change,
+                    Notional::from(-1.0),
+                )]),
             ),
             (
                 "B",
-                HashMap::from([(ScoringFactor::PriceEma200Change, Notional::from(-1.0))]),
+                HashMap::from([(
+                    ScoringFactor::SnapshotPriceEma200Change,
+                    Notional::from(-1.0),
+                )]),
             ),
             (
                 "C",
-                HashMap::from([(ScoringFactor::PriceEma20Change, Notional::from(1.0))]),
+                HashMap::from([(ScoringFactor::SnapshotPriceEma20Change, Notional::from(1.0))]),
             ),
             (
                 "D",
-                HashMap::from([(ScoringFactor::PriceEma20Change, Notional::from(0.0))]),
+                HashMap::from([(ScoringFactor::SnapshotPriceEma20Change, Notional::from(0.0))]),
             ),
         ]
         .into();
@@ -76,7 +82,7 @@ mod test {
             .return_const_st(expected_scores.clone());
         let ranker = NegativeLeastWinningRanker {
             notional_ranker,
-            factor_type: ScoringFactor::PriceEma20Change,
+            factor_type: ScoringFactor::SnapshotPriceEma20Change,
         };
 
         // When
@@ -99,7 +105,7 @@ mod test {
             .return_const_st(expected_scores.clone());
         let ranker = NegativeLeastWinningRanker {
             notional_ranker,
-            factor_type: ScoringFactor::PriceEma20Change,
+            factor_type: ScoringFactor::SnapshotPriceEma20Change,
         };
 
         // When
@@ -5,7 +5,7 @@ use tokio::process::Command;
 pub struct TablePrinter;
 
 impl TablePrinter {
-    pub async fn print<T: Serialize>(&self, table: &T) -> anyhow::Result<()> {
+    pub async fn print<T: Serialize + ?Sized>(&self, table: &T) -> anyhow::Result<()> {
         let successful = build_process_to_print(table)?
             .status()
             .await
@@ -18,7 +18,7 @@ impl TablePrinter {
     }
 }
 
-fn build_process_to_print<T: Serialize>(table: &T) -> anyhow::Result<Command> {
+fn build_process_to_print<T: Serialize + ?Sized>(table: &T) -> anyhow::Result<Command> {
     let json = serde_json::to_string(table)?;
     let b64_encoded = base64::encode(json);
     let js = format!(
@@ -0,0 +1,197 @@
+//! Technical indicators over a series of prices sorted from the oldest to the latest.
+//!
+//! Each function returns the indicator at the latest price, or `None` if the series is too short.
+
+/// Trading days in a year, used for annualizing and for 52-week ranges of daily bars.
+pub const TRADING_DAYS_PER_YEAR: usize = 252;
+
+/// Simple Moving Average over the last `length` values.
+pub fn sma(values: &[f64], length: usize) -> Option<f64> {
+    if length == 0 || values.len() < length {
+        return None;
+    }
+    let window = &values[values.len() - length..];
+    Some(window.iter().sum::<f64>() / length as f64)
+}
+
+/// Exponential Moving Average, seeded with the SMA of the first `length` values.
+pub fn ema(values: &[f64], length: usize) -> Option<f64> {
+    ema_series(values, length).last().copied()
+}
+
+/// EMA at every value from the `length`-th on.
+fn ema_series(values: &[f64], length: usize) -> Vec<f64> {
+    let Some(seed) = sma(&values[..length.min(values.len())], length) else {
+        return Vec::default();
+    };
+    let alpha = 2.0 / (length as f64 + 1.0);
+    let rest = values[length..].iter().scan(seed, |ema, value| {
+        *ema += alpha * (value - *ema);
+        Some(*ema)
+    });
+    std::iter::once(seed).chain(rest).collect()
+}
+
+/// Relative Strength Index with Wilder's smoothing, from 0 to 100.
+pub fn rsi(values: &[f64], length: usize) -> Option<f64> {
+    if length == 0 || values.len() <= length {
+        return None;
+    }
+    let changes: Vec<_> = values.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let smooth = |average: f64, value: f64| (average * (length - 1) as f64 + value) / length as f64;
+    let (initial_changes, rest) = changes.split_at(length);
+    let mut gain = initial_changes.iter().map(|c| c.max(0.0)).sum::<f64>() / length as f64;
+    let mut loss = initial_changes.iter().map(|c| (-c).max(0.0)).sum::<f64>() / length as f64;
+    for change in rest {
+        gain = smooth(gain, change.max(0.0));
+        loss = smooth(loss, (-change).max(0.0));
+    }
+    let result = if loss == 0.0 {
+        if gain == 0.0 {
+            50.0
+        } else {
+            100.0
+        }
+    } else {
+        100.0 - 100.0 / (1.0 + gain / loss)
+    };
+    Some(result)
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Macd {
+    pub macd: f64,
+    pub signal: f64,
+    pub histogram: f64,
+}
+
+/// Moving Average Convergence Divergence, usually with the lengths 12, 26 and 9.
+pub fn macd(values: &[f64], fast: usize, slow: usize, signal: usize) -> Option<Macd> {
+    let fast_ema = ema_series(values, fast);
+    let slow_ema = ema_series(values, slow);
+    let offset = fast_ema.len().checked_sub(slow_ema.len())?;
+    let macd_line: Vec<_> = fast_ema[offset..]
+        .iter()
+        .zip(&slow_ema)
+        .map(|(fast, slow)| fast - slow)
+        .collect();
+    let signal = ema(&macd_line, signal)?;
+    let macd = *macd_line.last()?;
+    let result = Macd {
+        macd,
+        signal,
+        histogram: macd - signal,
+    };
+    Some(result)
+}
+
+/// Position of the latest value within the Bollinger Bands, 0 at the lower band and 1 at the upper.
+pub fn bollinger_percent_b(values: &[f64], length: usize, width: f64) -> Option<f64> {
+    let mean = sma(values, length)?;
+    let window = &values[values.len() - length..];
+    let variance = window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / length as f64;
+    let band = width * variance.sqrt();
+    if band == 0.0 {
+        return None;
+    }
+    Some((values.last()? - (mean - band)) / (2.0 * band))
+}
+
+/// Change of `price` from the highest of the last `length` values, 0 or negative.
+pub fn drawdown_from_high(highs: &[f64], price: f64, length: usize) -> Option<f64> {
+    let window = &highs[highs.len().saturating_sub(length)..];
+    let high = window.iter().copied().reduce(f64::max)?;
+    (high > 0.0).then(|| (price / high - 1.0).min(0.0))
+}
+
+/// Annualized standard deviation of the daily log returns over the last `length` days.
+pub fn realized_volatility(values: &[f64], length: usize) -> Option<f64> {
+    if length < 2 || values.len() <= length {
+        return None;
+    }
+    let returns: Vec<_> = values[values.len() - length - 1..]
+        .windows(2)
+        .map(|pair| (pair[1] / pair[0]).ln())
+        .collect();
+    let mean = returns.iter().sum::<f64>() / length as f64;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (length - 1) as f64;
+    Some((variance * TRADING_DAYS_PER_YEAR as f64).sqrt())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(expected: f64, actual: Option<f64>) {
+        let actual = actual.unwrap();
+        assert!(
+            (expected - actual).abs() < 1e-9,
+            "Expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test_case::case(&[1.0, 2.0, 3.0], 2 => Some(2.5) ; "Last window")]
+    #[test_case::case(&[1.0, 2.0, 3.0], 4 => None      ; "Too short")]
+    #[test_case::case(&[1.0, 2.0, 3.0], 0 => None      ; "Zero length")]
+    fn sma(values: &[f64], length: usize) -> Option<f64> {
+        super::sma(values, length)
+    }
+
+    #[test]
+    fn ema() {
+        // Seeded with 2.0, then 2.0 + 0.5 * (4.0 - 2.0) = 3.0, then 3.0 + 0.5 * (1.0 - 3.0) = 2.0
+        assert_close(2.0, super::ema(&[1.0, 2.0, 3.0, 4.0, 1.0], 3));
+        assert_eq!(None, super::ema(&[1.0], 3));
+    }
+
+    #[test]
+    fn rsi() {
+        assert_close(100.0, super::rsi(&[1.0, 2.0, 3.0, 4.0], 3));
+        assert_close(50.0, super::rsi(&[1.0, 1.0, 1.0, 1.0], 3));
+        assert_close(50.0, super::rsi(&[1.0, 2.0, 1.0], 2));
+        assert_eq!(None, super::rsi(&[1.0, 2.0, 3.0], 3));
+    }
+
+    #[test]
+    fn macd_of_linear_prices() {
+        // EMAs of a linear series lag by a constant, so the MACD line is flat
+        let values: Vec<_> = (0..50).map(f64::from).collect();
+        let macd = super::macd(&values, 3, 5, 2).unwrap();
+        assert!((macd.macd - 1.0).abs() < 1e-9);
+        assert!(macd.histogram.abs() < 1e-9);
+    }
+
+    #[test]
+    fn macd_of_short_series() {
+        assert_eq!(None, super::macd(&[1.0, 2.0, 3.0], 3, 5, 2));
+    }
+
+    #[test]
+    fn bollinger_percent_b() {
+        // Mean 2, standard deviation 1, so the bands with width 1 are 1 and 3
+        assert_close(
+            1.0,
+            super::bollinger_percent_b(&[1.0, 3.0, 1.0, 3.0], 4, 1.0),
+        );
+        assert_eq!(None, super::bollinger_percent_b(&[1.0, 1.0], 2, 2.0));
+    }
+
+    #[test]
+    fn drawdown_from_high() {
+        assert_close(-0.75, super::drawdown_from_high(&[8.0, 4.0, 2.0], 2.0, 3));
+        assert_close(-0.5, super::drawdown_from_high(&[8.0, 4.0, 2.0], 2.0, 2));
+        assert_close(0.0, super::drawdown_from_high(&[8.0, 4.0, 2.0], 9.0, 3));
+        assert_eq!(None, super::drawdown_from_high(&[], 2.0, 3));
+    }
+
+    #[test]
+    fn realized_volatility() {
+        let e = std::f64::consts::E;
+        assert_close(0.0, super::realized_volatility(&[1.0, e, e * e], 2));
+        let expected = (2.0 * TRADING_DAYS_PER_YEAR as f64).sqrt();
+        assert_close(expected, super::realized_volatility(&[1.0, e, 1.0], 2));
+        assert_eq!(None, super::realized_volatility(&[1.0, 2.0], 2));
+    }
+}
@@ -1,96 +1,742 @@
 use crate::arithmetic_renderer::ArithmeticRenderer;
-use crate::ibkr_client::IbkrClient;
+use crate::config::Config;
+use crate::ibkr_client::SessionKeeper;
+use crate::ibkr_error::IbkrError;
+use crate::invest_advisor::InvestAdviceEntry;
 use crate::invest_advisor::InvestAdvisor;
+use crate::market_calendar::MarketCalendar;
+use crate::market_scanner::MarketScanner;
+use crate::report_renderer::FilteredPositionEntry;
+use crate::report_renderer::IncompleteSnapshotEntry;
+use crate::report_renderer::ReportEntry;
 use crate::report_renderer::ReportRenderer;
+use crate::run_archive::Run;
+use crate::run_archive::RunArchive;
+use crate::scoring_factor_extractor::ScoringFactor;
 use crate::scoring_factor_extractor::ScoringFactorExtractor;
+use crate::statement_importer::StatementImporter;
+use crate::stock_data_cacher::DataSource;
+use crate::stock_data_cacher::FetchedStockData;
+use crate::stock_data_cacher::RefreshPolicy;
 use crate::stock_data_cacher::StockDataCacher;
+use crate::stock_data_downloader::DownloadError;
+use crate::stock_data_downloader::StockData;
+use crate::stock_data_downloader::StockDataDownloader;
 use crate::stock_ranker::StockRanker;
+use crate::stock_ranker::Ticker;
 use crate::table_printer::TablePrinter;
 use anyhow::Context;
 use clap::Parser;
+use clap::Subcommand;
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+#[mockall_double::double]
+use crate::ibkr_client::IbkrClient;
+
+#[mockall_double::double]
+use crate::clock::Clock;
 
 pub struct Toy {
     args: Cli,
     ranker: StockRanker,
     table_printer: TablePrinter,
     report_renderer: ReportRenderer,
-    ibkr_client: IbkrClient,
+    ibkr_client: Rc<IbkrClient>,
     stock_data_cacher: StockDataCacher,
     scoring_factor_extractor: ScoringFactorExtractor,
     invest_advisor: InvestAdvisor,
+    run_archive: RunArchive,
+    statement_importer: StatementImporter,
+    downloader: Rc<StockDataDownloader>,
+    market_scanner: MarketScanner,
+    clock: Clock,
 }
 
 impl Toy {
-    pub fn new(args: Cli) -> Self {
-        Self {
+    pub fn new(args: Cli, config: Config) -> anyhow::Result<Self> {
+        let ibkr_client: Rc<_> = IbkrClient::new(&config.gateway)?.into();
+        let statement_importer = StatementImporter::new(config.portfolio.clone());
+        let scoring_factor_extractor =
+            ScoringFactorExtractor::new(&config.ranking, &config.history);
+        let downloader: Rc<_> = StockDataDownloader::new(
+            ibkr_client.clone(),
+            config.snapshot,
+            config.history,
+            config.portfolio,
+            config.universe,
+            &config.cache,
+            &config.ranking,
+        )
+        .into();
+        let market_scanner = MarketScanner::new(ibkr_client.clone(), config.scanners);
+        let result = Self {
             args,
-            ranker: Default::default(),
+            ranker: StockRanker::new(&config.ranking),
             table_printer: TablePrinter,
-            report_renderer: ReportRenderer {
-                arithmetic_renderer: ArithmeticRenderer,
-            },
-            ibkr_client: Default::default(),
-            stock_data_cacher: StockDataCacher::default(),
-            scoring_factor_extractor: ScoringFactorExtractor,
+            report_renderer: ReportRenderer::new(&config.ranking),
+            ibkr_client,
+            stock_data_cacher: StockDataCacher::new(
+                downloader.clone(),
+                &config.cache,
+                MarketCalendar::new(&config.calendar.exchanges),
+            ),
+            scoring_factor_extractor,
             invest_advisor: InvestAdvisor {
                 arithmetic_renderer: ArithmeticRenderer,
             },
-        }
+            run_archive: RunArchive::new(config.archive.dir()),
+            statement_importer,
+            downloader,
+            market_scanner,
+            clock: Default::default(),
+        };
+        Ok(result)
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
-        // Some API requires querying this endpoint first
-        let iserver_accounts = self.ibkr_client.i_server_accounts().await?;
-        if iserver_accounts.accounts.is_empty() {
-            anyhow::bail!("No brokerage account found");
+        match &self.args.command {
+            Some(Command::Cache { command }) => self.run_cache_command(command).await,
+            Some(Command::History { command }) => self.run_history_command(command).await,
+            Some(Command::Scan { scanners }) => self.run_scan(scanners).await,
+            None => self.run_report().await,
         }
+    }
 
-        let portfolio_accounts = self.ibkr_client.portfolio_accounts().await?;
-        let account_id = portfolio_accounts
-            .into_iter()
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("No default account found"))?
-            .accountId;
-        println!("Account ID: {}", &account_id);
+    async fn run_cache_command(&self, command: &CacheCommand) -> anyhow::Result<()> {
+        match command {
+            CacheCommand::List => {
+                let entries = self.stock_data_cacher.list().await?;
+                if entries.is_empty() {
+                    println!("Cache is empty");
+                } else {
+                    self.table_printer.print(&entries).await?;
+                }
+            }
+            CacheCommand::Show { account } => {
+                let stock_data = self.stock_data_cacher.show(account).await?;
+                println!("{}", serde_json::to_string_pretty(&stock_data)?);
+            }
+            CacheCommand::Clear { account } => {
+                let cleared = self.stock_data_cacher.clear(account.as_deref()).await?;
+                println!("Cleared cache of {} accounts", cleared.len());
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_history_command(&self, command: &HistoryCommand) -> anyhow::Result<()> {
+        match command {
+            HistoryCommand::List => {
+                let entries = self.run_archive.summarize().await?;
+                if entries.is_empty() {
+                    println!("No run recorded");
+                } else {
+                    self.table_printer.print(&entries).await?;
+                }
+            }
+            HistoryCommand::Show { run } => {
+                let run = self.run_archive.run(*run).await?;
+                println!(
+                    "Run at {} of account {}",
+                    run.timestamp.to_rfc3339(),
+                    run.account
+                );
+                let report = self.report_renderer.render(
+                    &run.stock_data,
+                    &run.candidates(),
+                    &run.scores(),
+                    &run.accounts(),
+                );
+                let incomplete_snapshots = self
+                    .report_renderer
+                    .render_incomplete_snapshots(&run.stock_data);
+                let filtered_positions = self
+                    .report_renderer
+                    .render_filtered_positions(&run.stock_data);
+                self.print_report(
+                    &report,
+                    &incomplete_snapshots,
+                    &filtered_positions,
+                    &run.advice,
+                )
+                .await?;
+            }
+            HistoryCommand::Factor { ticker, factor } => {
+                let points = self.run_archive.factor_history(ticker, *factor).await?;
+                if points.is_empty() {
+                    println!("No run recorded this factor of {}", ticker);
+                } else {
+                    self.table_printer.print(&points).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_report(&self) -> anyhow::Result<()> {
+        let fetched = if self.args.statements.is_empty() {
+            self.fetch_holdings().await?
+        } else {
+            self.import_holdings().await?
+        };
+        let mut holdings = Vec::with_capacity(fetched.len());
+        let mut data_sources = Vec::with_capacity(fetched.len());
+        for (account_id, fetched) in fetched {
+            data_sources.push(DataSourceEntry {
+                account: account_id.clone(),
+                source: fetched.source.to_string(),
+                timestamp: fetched.stock_data.timestamp.to_rfc3339(),
+            });
+            holdings.push((account_id, fetched.stock_data));
+        }
+        let groups = if self.args.rank_per_account {
+            holdings.into_iter().map(|holding| vec![holding]).collect()
+        } else {
+            vec![holdings]
+        };
+
+        let mut report = Vec::new();
+        let mut incomplete_snapshots = Vec::new();
+        let mut filtered_positions = Vec::new();
+        let mut invest_advices = Vec::new();
+        for group in groups {
+            let accounts = label_accounts(&group);
+            let account = group.iter().map(|(account_id, _)| account_id).join(", ");
+            let stock_data = StockData::merge(group.iter().map(|(_, stock_data)| stock_data));
+            let candidates = self
+                .scoring_factor_extractor
+                .extract_scoring_factors(&stock_data)
+                .await;
+            let scores = self.ranker.rank(&candidates);
+            report.extend(self.report_renderer.render(
+                &stock_data,
+                &candidates,
+                &scores,
+                &accounts,
+            ));
+            incomplete_snapshots.extend(
+                self.report_renderer
+                    .render_incomplete_snapshots(&stock_data),
+            );
+            filtered_positions.extend(self.report_renderer.render_filtered_positions(&stock_data));
+            let advices =
+                self.invest_advisor
+                    .render_advice(&scores, self.args.invest_num, &accounts);
+
+            let run = Run::new(
+                self.clock.now(),
+                account,
+                stock_data,
+                &candidates,
+                &scores,
+                &accounts,
+                advices,
+            );
+            if let Err(e) = self.run_archive.append(&run).await {
+                eprintln!("Warning: Failed to record the run: {:#}", e);
+            }
+            invest_advices.extend(run.advice);
+        }
+
+        println!();
+        println!("============");
+        println!("Data sources");
+        println!("============");
+        self.table_printer.print(&data_sources).await?;
+
+        self.print_report(
+            &report,
+            &incomplete_snapshots,
+            &filtered_positions,
+            &invest_advices,
+        )
+        .await?;
+
+        let retry_count = self.ibkr_client.retry_count();
+        if retry_count > 0 {
+            println!();
+            println!("Retried {} requests to the gateway", retry_count);
+        }
+
+        Ok(())
+    }
 
+    /// Ranks the stocks found by the scanners as if buying them anew.
+    async fn run_scan(&self, scanners: &[String]) -> anyhow::Result<()> {
+        self.ibkr_client.ensure_session().await?;
+        let _session_keeper = self.ibkr_client.keep_session_alive();
+
+        // Some API requires querying this endpoint first
+        self.ibkr_client.i_server_accounts().await?;
+
+        let universe = self.market_scanner.scan(scanners).await?;
+        if universe.is_empty() {
+            println!("Scanners found no stock");
+            return Ok(());
+        }
         let stock_data = self
-            .stock_data_cacher
-            .fetch(&account_id, self.args.use_cache)
+            .downloader
+            .download_universe(universe)
             .await
-            .context("Failed to fetch stock data")?;
+            .context("Failed to download stock data")?;
+        let accounts = HashMap::default();
         let candidates = self
             .scoring_factor_extractor
-            .extract_scoring_factors(&stock_data);
+            .extract_scoring_factors(&stock_data)
+            .await;
         let scores = self.ranker.rank(&candidates);
-        let report = self.report_renderer.render(&candidates, &scores);
+        let report = self
+            .report_renderer
+            .render(&stock_data, &candidates, &scores, &accounts);
+        let incomplete_snapshots = self
+            .report_renderer
+            .render_incomplete_snapshots(&stock_data);
+        let advices = self
+            .invest_advisor
+            .render_advice(&scores, self.args.invest_num, &accounts);
+        self.print_report(&report, &incomplete_snapshots, &[], &advices)
+            .await
+    }
+
+    /// Stock data of the selected accounts from the gateway or the cache.
+    async fn fetch_holdings(&self) -> anyhow::Result<Vec<(String, FetchedStockData)>> {
+        let policy = self.args.refresh_policy();
+        let (available_accounts, _session_keeper) = match policy {
+            // Offline, so the accounts are the cached ones
+            RefreshPolicy::Never => (self.stock_data_cacher.cached_account_ids().await?, None),
+            // The gateway is only needed for the accounts without a cache
+            RefreshPolicy::PreferCache => match self.gateway_accounts().await {
+                Ok((accounts, session_keeper)) => (accounts, Some(session_keeper)),
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Choosing among the cached accounts as the gateway is unavailable: {:#}",
+                        e
+                    );
+                    (self.stock_data_cacher.cached_account_ids().await?, None)
+                }
+            },
+            RefreshPolicy::Always | RefreshPolicy::IfStale => {
+                let (accounts, session_keeper) = self.gateway_accounts().await?;
+                (accounts, Some(session_keeper))
+            }
+        };
+        let account_ids = select_accounts(
+            available_accounts,
+            &self.args.accounts,
+            self.args.all_accounts,
+        )?;
 
+        let mut result = Vec::with_capacity(account_ids.len());
+        for account_id in account_ids {
+            println!("Account ID: {}", &account_id);
+            let fetched = self
+                .stock_data_cacher
+                .fetch(&account_id, policy)
+                .await
+                .with_context(|| format!("Failed to fetch stock data of account {}", account_id))?;
+            result.push((account_id, fetched));
+        }
+        Ok(result)
+    }
+
+    /// Accounts of the gateway session, kept alive as long as the returned keeper.
+    async fn gateway_accounts(&self) -> anyhow::Result<(Vec<String>, SessionKeeper)> {
+        self.ibkr_client.ensure_session().await?;
+        let session_keeper = self.ibkr_client.keep_session_alive();
+
+        // Some API requires querying this endpoint first
+        let iserver_accounts = self.ibkr_client.i_server_accounts().await?;
+        if iserver_accounts.accounts.is_empty() {
+            anyhow::bail!("No brokerage account found");
+        }
+
+        let portfolio_accounts = self
+            .ibkr_client
+            .portfolio_accounts()
+            .await?
+            .into_iter()
+            .map(|account| account.accountId)
+            .collect();
+        Ok((portfolio_accounts, session_keeper))
+    }
+
+    /// Stock data of the selected accounts from the statement files.
+    async fn import_holdings(&self) -> anyhow::Result<Vec<(String, FetchedStockData)>> {
+        let mut imported: HashMap<String, StockData> = HashMap::new();
+        let mut available_accounts = Vec::new();
+        for path in &self.args.statements {
+            for (account_id, stock_data) in self.statement_importer.import(path).await? {
+                if imported.contains_key(&account_id) {
+                    anyhow::bail!("Account {} is in more than one statement", account_id);
+                }
+                available_accounts.push(account_id.clone());
+                imported.insert(account_id, stock_data);
+            }
+        }
+        let account_ids = select_accounts(
+            available_accounts,
+            &self.args.accounts,
+            self.args.all_accounts,
+        )?;
+        let result = account_ids
+            .into_iter()
+            .filter_map(|account_id| {
+                let stock_data = imported.remove(&account_id)?;
+                let fetched = FetchedStockData {
+                    stock_data,
+                    source: DataSource::Statement,
+                };
+                Some((account_id, fetched))
+            })
+            .collect();
+        Ok(result)
+    }
+
+    async fn print_report(
+        &self,
+        report: &[ReportEntry],
+        incomplete_snapshots: &[IncompleteSnapshotEntry],
+        filtered_positions: &[FilteredPositionEntry],
+        invest_advices: &[InvestAdviceEntry],
+    ) -> anyhow::Result<()> {
         println!();
         println!("=============");
         println!("Score details");
         println!("=============");
-        self.table_printer.print(&report).await?;
+        self.table_printer.print(report).await?;
 
-        let invest_advices = self
-            .invest_advisor
-            .render_advice(&scores, self.args.invest_num);
+        if !incomplete_snapshots.is_empty() {
+            println!();
+            println!("===========================");
+            println!("Incomplete market snapshots");
+            println!("===========================");
+            self.table_printer.print(incomplete_snapshots).await?;
+        }
+
+        if !filtered_positions.is_empty() {
+            println!();
+            println!("==================");
+            println!("Filtered positions");
+            println!("==================");
+            self.table_printer.print(filtered_positions).await?;
+        }
 
         println!();
         println!("==================");
         println!("Investment advices");
         println!("==================");
-        self.table_printer.print(&invest_advices).await?;
+        self.table_printer.print(invest_advices).await?;
 
         Ok(())
     }
 }
 
+/// Where the stock data of an account comes from, stated at the top of the report.
+#[derive(Serialize)]
+struct DataSourceEntry {
+    account: String,
+    source: String,
+    timestamp: String,
+}
+
+/// Picks the accounts to report on among those available.
+///
+/// Without any selection, only the first account is chosen.
+fn select_accounts(
+    available: Vec<String>,
+    selected: &[String],
+    all_accounts: bool,
+) -> anyhow::Result<Vec<String>> {
+    if all_accounts {
+        if available.is_empty() {
+            anyhow::bail!("No account found");
+        }
+        return Ok(available);
+    }
+    if selected.is_empty() {
+        let default_account = available
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No default account found"))?;
+        return Ok(vec![default_account]);
+    }
+    if let Some(unknown) = selected.iter().find(|id| !available.contains(id)) {
+        anyhow::bail!(
+            "Account {} not found, available accounts: {}",
+            unknown,
+            available.join(", ")
+        );
+    }
+    Ok(selected.iter().unique().cloned().collect())
+}
+
+/// Lists the accounts holding each ticker.
+fn label_accounts(holdings: &[(String, StockData)]) -> HashMap<Ticker, String> {
+    let mut accounts: HashMap<Ticker, Vec<&str>> = HashMap::new();
+    for (account_id, stock_data) in holdings {
+        for position in &stock_data.portfolio {
+            accounts
+                .entry(position.ticker.as_str().into())
+                .or_default()
+                .push(account_id);
+        }
+    }
+    accounts
+        .into_iter()
+        .map(|(ticker, account_ids)| (ticker, account_ids.join(", ")))
+        .collect()
+}
+
+/// Kind of failure, told apart by the process exit code so that wrapper scripts can react.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Failure {
+    Other = 1,
+    GatewayUnreachable = 10,
+    NotAuthenticated = 11,
+    GatewayError = 12,
+    UntrustedGateway = 13,
+    MalformedData = 14,
+}
+
+impl Failure {
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<IbkrError>() {
+                    let failure = match e {
+                        IbkrError::GatewayUnreachable { .. } => Self::GatewayUnreachable,
+                        IbkrError::NotAuthenticated { .. } => Self::NotAuthenticated,
+                        IbkrError::Request { .. }
+                        | IbkrError::Timeout { .. }
+                        | IbkrError::Http { .. } => Self::GatewayError,
+                        IbkrError::Tls { .. } => Self::UntrustedGateway,
+                        IbkrError::MalformedResponse { .. } => Self::MalformedData,
+                    };
+                    Some(failure)
+                } else {
+                    match cause.downcast_ref::<DownloadError>() {
+                        Some(DownloadError::MissingContractId { .. }) => Some(Self::MalformedData),
+                        _ => None,
+                    }
+                }
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    /// What the user can do about it.
+    pub fn hint(&self) -> Option<&'static str> {
+        let hint = match self {
+            Self::Other => return None,
+            Self::GatewayUnreachable => {
+                "Start the gateway or fix `gateway.base-url` in the config."
+            }
+            Self::NotAuthenticated => "Log in through the gateway in a browser and run again.",
+            Self::GatewayError => "Try again later or restart the gateway.",
+            Self::UntrustedGateway => "Check the TLS settings of `gateway` in the config.",
+            Self::MalformedData => "IBKR returned data in an unexpected format.",
+        };
+        Some(hint)
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(*self as u8)
+    }
+}
+
 #[derive(Parser)]
 pub struct Cli {
-    /// Generates report using cached data
+    /// Path to the configuration file.
     #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// When to download stock data instead of using the cache.
+    #[arg(long, value_enum, default_value = "always")]
+    pub refresh: RefreshPolicy,
+
+    /// Same as `--refresh if-stale`.
+    #[arg(long, conflicts_with = "refresh")]
     pub use_cache: bool,
 
+    /// Activity Statement in CSV or Flex Query in XML to read instead of the gateway, can be
+    /// repeated.
+    #[arg(long = "statement", value_name = "FILE", conflicts_with_all = ["refresh", "use_cache"])]
+    pub statements: Vec<PathBuf>,
+
     /// Number of stocks to invest.
     #[arg(long, default_value = "16")]
     pub invest_num: usize,
+
+    /// Account to report on, can be repeated. Defaults to the first account.
+    #[arg(long = "account", value_name = "ID")]
+    pub accounts: Vec<String>,
+
+    /// Reports on all accounts.
+    #[arg(long, conflicts_with = "accounts")]
+    pub all_accounts: bool,
+
+    /// Ranks the holdings of each account separately instead of merging them.
+    #[arg(long)]
+    pub rank_per_account: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    fn refresh_policy(&self) -> RefreshPolicy {
+        if self.use_cache {
+            RefreshPolicy::IfStale
+        } else {
+            self.refresh
+        }
+    }
+}
+
+/// Without a command, ranks the stocks and prints the report.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspects or purges the cached stock data.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Lists past runs, re-renders their reports or tracks a scoring factor over them.
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+
+    /// Ranks the stocks found by the market scanners, or by all of them if none is named.
+    Scan { scanners: Vec<String> },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Lists the cached accounts and whether their data is fresh.
+    List,
+
+    /// Prints the cached stock data of an account.
+    Show { account: String },
+
+    /// Deletes the cached stock data of an account, or of all accounts.
+    Clear { account: Option<String> },
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// Lists the recorded runs, numbered from the oldest.
+    List,
+
+    /// Prints the report of a run again.
+    Show { run: usize },
+
+    /// Prints a scoring factor of a ticker in every run, e.g. `AAPL rsi14`.
+    Factor {
+        ticker: String,
+        factor: ScoringFactor,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ibkr_client::PortfolioPosition;
+    use anyhow::Context;
+    use reqwest::StatusCode;
+
+    fn build_not_authenticated_error() -> anyhow::Error {
+        IbkrError::NotAuthenticated {
+            reason: Default::default(),
+            login_url: Default::default(),
+        }
+        .into()
+    }
+
+    fn build_download_http_error() -> anyhow::Error {
+        let error: DownloadError = IbkrError::Http {
+            endpoint: Default::default(),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            body: Default::default(),
+        }
+        .into();
+        Err::<(), _>(error)
+            .context("Failed to download stock data")
+            .unwrap_err()
+    }
+
+    fn build_missing_contract_id_error() -> anyhow::Error {
+        DownloadError::MissingContractId {
+            snapshot: Default::default(),
+        }
+        .into()
+    }
+
+    #[test_case::case(build_not_authenticated_error() => Failure::NotAuthenticated ; "Not authenticated")]
+    #[test_case::case(build_download_http_error()     => Failure::GatewayError     ; "Wrapped in context")]
+    #[test_case::case(build_missing_contract_id_error() => Failure::MalformedData  ; "Missing contract ID")]
+    #[test_case::case(anyhow::anyhow!("No account")    => Failure::Other            ; "Other")]
+    fn failure_of(error: anyhow::Error) -> Failure {
+        Failure::of(&error)
+    }
+
+    fn build_available_accounts() -> Vec<String> {
+        ["U1", "U2", "U3"].into_iter().map(String::from).collect()
+    }
+
+    #[test_case::case(&[],           false => vec!["U1"]             ; "First by default")]
+    #[test_case::case(&["U3", "U2"], false => vec!["U3", "U2"]       ; "Selected")]
+    #[test_case::case(&["U2", "U2"], false => vec!["U2"]             ; "Selected twice")]
+    #[test_case::case(&[],           true  => vec!["U1", "U2", "U3"] ; "All")]
+    fn select_accounts(selected: &[&str], all_accounts: bool) -> Vec<String> {
+        let selected: Vec<String> = selected.iter().map(|id| id.to_string()).collect();
+        super::select_accounts(build_available_accounts(), &selected, all_accounts).unwrap()
+    }
+
+    #[test]
+    fn select_unknown_account() {
+        let error =
+            super::select_accounts(build_available_accounts(), &["U4".into()], false).unwrap_err();
+        assert_eq!(
+            "Account U4 not found, available accounts: U1, U2, U3",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn label_accounts() {
+        // Given
+        let stock_data = |tickers: &[&str]| StockData {
+            portfolio: tickers
+                .iter()
+                .map(|ticker| PortfolioPosition {
+                    ticker: ticker.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let holdings = [
+            ("U1".to_string(), stock_data(&["A", "B"])),
+            ("U2".to_string(), stock_data(&["B"])),
+        ];
+        let expected_accounts: HashMap<Ticker, String> = [
+            ("A".into(), "U1".to_string()),
+            ("B".into(), "U1, U2".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        // When
+        let actual_accounts = super::label_accounts(&holdings);
+
+        // Then
+        assert_eq!(expected_accounts, actual_accounts);
+    }
 }
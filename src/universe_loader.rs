@@ -0,0 +1,259 @@
+use crate::config::UniverseConfig;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::rc::Rc;
+
+#[mockall_double::double]
+use crate::ibkr_client::IbkrClient;
+
+/// Stock not held yet, ranked alongside the holdings.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UniverseEntry {
+    pub conid: i64,
+    pub ticker: String,
+}
+
+/// Loads the stocks to rank besides the holdings from IBKR watchlists and a local file.
+#[cfg_attr(test, derive(Default))]
+pub struct UniverseLoader {
+    ibkr_client: Rc<IbkrClient>,
+    config: UniverseConfig,
+}
+
+#[mockall::automock]
+impl UniverseLoader {
+    pub fn new(ibkr_client: Rc<IbkrClient>, config: UniverseConfig) -> Self {
+        Self {
+            ibkr_client,
+            config,
+        }
+    }
+
+    /// Stocks of all configured sources without duplicates.
+    ///
+    /// Sources failing to load are left out with a warning.
+    pub async fn load(&self) -> Vec<UniverseEntry> {
+        let mut entries = Vec::default();
+        if !self.config.watchlists.is_empty() {
+            match self.load_watchlists().await {
+                Ok(watchlist_entries) => entries.extend(watchlist_entries),
+                Err(e) => eprintln!("Warning: Failed to load the watchlists: {:#}", e),
+            }
+        }
+        if let Some(path) = &self.config.file {
+            match load_file(path).await {
+                Ok(file_entries) => entries.extend(file_entries),
+                Err(e) => eprintln!("Warning: Failed to load {}: {:#}", path.display(), e),
+            }
+        }
+        let mut conids = HashSet::new();
+        entries.retain(|entry| conids.insert(entry.conid));
+        entries
+    }
+}
+
+impl UniverseLoader {
+    async fn load_watchlists(&self) -> anyhow::Result<Vec<UniverseEntry>> {
+        let summaries = self.ibkr_client.watchlists().await?.data.user_lists;
+        let mut entries = Vec::default();
+        for name in &self.config.watchlists {
+            let Some(summary) = summaries.iter().find(|summary| &summary.name == name) else {
+                eprintln!("Warning: Watchlist {} not found", name);
+                continue;
+            };
+            let watchlist = match self.ibkr_client.watchlist(&summary.id).await {
+                Ok(watchlist) => watchlist,
+                Err(e) => {
+                    eprintln!("Warning: Failed to load watchlist {}: {:#}", name, e);
+                    continue;
+                }
+            };
+            entries.extend(watchlist.instruments.into_iter().filter_map(|instrument| {
+                let entry = UniverseEntry {
+                    conid: instrument.conid?,
+                    ticker: instrument.ticker?,
+                };
+                Some(entry)
+            }));
+        }
+        Ok(entries)
+    }
+}
+
+async fn load_file(path: &Path) -> anyhow::Result<Vec<UniverseEntry>> {
+    let text = tokio::fs::read_to_string(path).await?;
+    parse_file(&text)
+}
+
+/// Parses one `TICKER,CONID` per line, skipping blank lines and comments starting with `#`.
+fn parse_file(text: &str) -> anyhow::Result<Vec<UniverseEntry>> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            let (ticker, conid) = line
+                .split_once(',')
+                .with_context(|| format!("Line {} is not `TICKER,CONID`", index + 1))?;
+            let entry = UniverseEntry {
+                conid: conid
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Malformed contract ID on line {}", index + 1))?,
+                ticker: ticker.trim().into(),
+            };
+            Ok(entry)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ibkr_client::MockIbkrClient;
+    use crate::ibkr_client::Watchlist;
+    use crate::ibkr_client::WatchlistInstrument;
+    use crate::ibkr_client::WatchlistSummary;
+    use crate::ibkr_client::Watchlists;
+    use crate::ibkr_client::WatchlistsData;
+    use crate::ibkr_error::IbkrError;
+    use mockall::predicate::*;
+
+    fn build_entry(ticker: &str, conid: i64) -> UniverseEntry {
+        UniverseEntry {
+            conid,
+            ticker: ticker.into(),
+        }
+    }
+
+    fn build_instrument(ticker: &str, conid: i64) -> WatchlistInstrument {
+        WatchlistInstrument {
+            conid: Some(conid),
+            ticker: Some(ticker.into()),
+        }
+    }
+
+    #[test]
+    fn parse_universe_file() {
+        let text = "# Ticker, contract ID\nAAPL,265598\n\n MSFT , 272093 \n";
+        assert_eq!(
+            vec![build_entry("AAPL", 265598), build_entry("MSFT", 272093)],
+            parse_file(text).unwrap()
+        );
+        assert!(parse_file("AAPL").is_err());
+        assert!(parse_file("AAPL,apple").is_err());
+    }
+
+    #[tokio::test]
+    async fn load_watchlists_without_duplicates() {
+        // Given
+        let mut ibkr_client = MockIbkrClient::default();
+        ibkr_client.expect_watchlists().return_once(|| {
+            let summaries = vec![
+                WatchlistSummary {
+                    id: "1".into(),
+                    name: "Tech".into(),
+                },
+                WatchlistSummary {
+                    id: "2".into(),
+                    name: "Banks".into(),
+                },
+            ];
+            Ok(Watchlists {
+                data: WatchlistsData {
+                    user_lists: summaries,
+                },
+            })
+        });
+        ibkr_client
+            .expect_watchlist()
+            .with(eq("1"))
+            .return_once(|_| {
+                let instruments = vec![
+                    build_instrument("AAPL", 265598),
+                    Default::default(),
+                    build_instrument("MSFT", 272093),
+                ];
+                Ok(Watchlist { instruments })
+            });
+        ibkr_client
+            .expect_watchlist()
+            .with(eq("2"))
+            .return_once(|_| {
+                let instruments = vec![build_instrument("AAPL", 265598)];
+                Ok(Watchlist { instruments })
+            });
+        let loader = UniverseLoader {
+            ibkr_client: ibkr_client.into(),
+            config: UniverseConfig {
+                watchlists: vec!["Tech".into(), "Banks".into(), "Missing".into()],
+                file: None,
+            },
+        };
+
+        // When
+        let entries = loader.load().await;
+
+        // Then
+        assert_eq!(
+            vec![build_entry("AAPL", 265598), build_entry("MSFT", 272093)],
+            entries
+        );
+    }
+
+    #[tokio::test]
+    async fn load_other_watchlists_when_one_fails() {
+        // Given
+        let mut ibkr_client = MockIbkrClient::default();
+        ibkr_client.expect_watchlists().return_once(|| {
+            let summaries = vec![
+                WatchlistSummary {
+                    id: "1".into(),
+                    name: "Tech".into(),
+                },
+                WatchlistSummary {
+                    id: "2".into(),
+                    name: "Banks".into(),
+                },
+            ];
+            Ok(Watchlists {
+                data: WatchlistsData {
+                    user_lists: summaries,
+                },
+            })
+        });
+        ibkr_client
+            .expect_watchlist()
+            .with(eq("1"))
+            .return_once(|_| {
+                Err(IbkrError::Http {
+                    endpoint: "iserver/watchlist?id=1".into(),
+                    status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+                    body: Default::default(),
+                })
+            });
+        ibkr_client
+            .expect_watchlist()
+            .with(eq("2"))
+            .return_once(|_| {
+                let instruments = vec![build_instrument("JPM", 1520593)];
+                Ok(Watchlist { instruments })
+            });
+        let loader = UniverseLoader {
+            ibkr_client: ibkr_client.into(),
+            config: UniverseConfig {
+                watchlists: vec!["Tech".into(), "Banks".into()],
+                file: None,
+            },
+        };
+
+        // When
+        let entries = loader.load().await;
+
+        // Then
+        assert_eq!(vec![build_entry("JPM", 1520593)], entries);
+    }
+}
@@ -0,0 +1,151 @@
+//! Data written to disk, wrapped in an envelope telling its schema version.
+//!
+//! Data written before the envelope existed has no `version` key and counts as version 1.
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Upgrades data by one version.
+pub type Migration = fn(Value) -> anyhow::Result<Value>;
+
+/// Data kept across runs of different versions of this program.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Name of the data in diagnostics.
+    const NAME: &'static str;
+
+    /// Migrations from version 1 on, the one at index `i` upgrading version `i + 1`.
+    const MIGRATIONS: &'static [Migration];
+
+    /// Version written by this build.
+    fn current_version() -> u32 {
+        Self::MIGRATIONS.len() as u32 + 1
+    }
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    version: u32,
+    data: Value,
+}
+
+pub fn to_string<T: Versioned>(data: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&EnvelopeRef {
+        version: T::current_version(),
+        data,
+    })
+}
+
+/// Parses data of any version up to the current one, migrating it on the way.
+pub fn from_str<T: Versioned>(text: &str) -> anyhow::Result<T> {
+    let value: Value = serde_json::from_str(text).context("Not valid JSON")?;
+    let current_version = T::current_version();
+    let (version, mut data) = match value.get("version") {
+        None => (1, value),
+        Some(version) => {
+            // Checked first as a newer envelope may carry more than the version and the data
+            if let Some(version) = version.as_u64().filter(|v| *v > current_version as u64) {
+                anyhow::bail!(
+                    "{} has schema version {}, but this build only reads up to version {}; upgrade ibkr-toy or delete the file",
+                    T::NAME,
+                    version,
+                    current_version
+                );
+            }
+            let Envelope { version, data } = serde_json::from_value(value)
+                .with_context(|| format!("{} has a malformed envelope", T::NAME))?;
+            (version, data)
+        }
+    };
+    if version == 0 {
+        anyhow::bail!("{} has schema version 0", T::NAME);
+    }
+    for (index, migration) in T::MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        data = migration(data).with_context(|| {
+            format!(
+                "Failed to migrate {} from schema version {}",
+                T::NAME,
+                index + 1
+            )
+        })?;
+    }
+    serde_json::from_value(data).with_context(|| {
+        format!(
+            "{} does not match schema version {} (migrated from version {})",
+            T::NAME,
+            current_version,
+            version
+        )
+    })
+}
+
+/// Adds the `fields` missing from a JSON object with their default values.
+pub fn fill_missing_fields(mut value: Value, fields: &[(&str, Value)]) -> anyhow::Result<Value> {
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Expected a JSON object"))?;
+    for (field, default) in fields {
+        object.entry(*field).or_insert_with(|| default.clone());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Sample {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    impl Versioned for Sample {
+        const NAME: &'static str = "Sample";
+        const MIGRATIONS: &'static [Migration] =
+            &[|value| fill_missing_fields(value, &[("tags", json!([]))])];
+    }
+
+    fn build_sample() -> Sample {
+        Sample {
+            name: "A".into(),
+            tags: Vec::default(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = to_string(&build_sample()).unwrap();
+        assert_eq!(r#"{"version":2,"data":{"name":"A","tags":[]}}"#, text);
+        assert_eq!(build_sample(), from_str::<Sample>(&text).unwrap());
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        assert_eq!(
+            build_sample(),
+            from_str::<Sample>(r#"{"name":"A"}"#).unwrap()
+        );
+    }
+
+    #[test_case::case(r#"{"version":3,"data":{}}"#           => "Sample has schema version 3, but this build only reads up to version 2; upgrade ibkr-toy or delete the file" ; "Newer version")]
+    #[test_case::case(r#"{"version":3,"data":{},"extra":1}"# => "Sample has schema version 3, but this build only reads up to version 2; upgrade ibkr-toy or delete the file" ; "Newer envelope")]
+    #[test_case::case(r#"{"version":"2","data":{}}"#         => "Sample has a malformed envelope: invalid type: string \"2\", expected u32" ; "Malformed version")]
+    #[test_case::case(r#"{"version":2}"#                     => "Sample has a malformed envelope: missing field `data`" ; "Missing data")]
+    #[test_case::case(r#"{"version":0,"data":{}}"#           => "Sample has schema version 0" ; "Version zero")]
+    #[test_case::case(r#"{"version":2,"data":{"name":"A"}}"# => "Sample does not match schema version 2 (migrated from version 2): missing field `tags`" ; "Malformed")]
+    #[test_case::case(r#"[]"#                                => "Failed to migrate Sample from schema version 1: Expected a JSON object" ; "Migration failed")]
+    fn incompatible(text: &str) -> String {
+        format!("{:#}", from_str::<Sample>(text).unwrap_err())
+    }
+}